@@ -2,65 +2,193 @@ extern crate rustpn;
 extern crate num;
 
 use rustpn::parse;
-use rustpn::vm::Vm;
+use rustpn::vm::{self, Vm};
 use rustpn::builtin;
-use std::io::{self, stdin, Read, BufRead};
+use std::io::{self, stdin, stdout, Read, BufRead, Write};
 use std::fs::File;
 use std::env;
+use std::process;
 use std::str::FromStr;
-use std::fmt::Display;
+use std::fmt::{Debug, Display};
 use num::bigint::BigInt;
 use num::integer::Integer;
+use num::{ToPrimitive, FromPrimitive};
+use num::{CheckedAdd, CheckedSub, CheckedMul, CheckedDiv};
 
-fn interactive<I>(vm: &mut Vm<I>) -> io::Result<()>
-        where I: Integer + Clone + FromStr + Display {
-    let stdin = stdin();
-    let mut program = String::new();
-    for line in stdin.lock().lines() {
+// Describe a parse error, prefixed with its `line:col` when known.
+fn describe_parse_error(e: &parse::Error) -> String {
+    let message = match *e {
+        parse::Error::LexError(e, _) => format!("lexer error: {}", e),
+        _ => format!("parser error: {}", e),
+    };
+    format!("{}: {}", e.position(), message)
+}
+
+// Read-eval-print loop over any input and output. Lines are kept in a pending
+// buffer until they form a complete program, prompting with `... ` for more;
+// the buffer is cleared once it is evaluated or found to be invalid. The loop
+// ends early if the program runs `quit`.
+fn repl<I, R, W>(vm: &mut Vm<I>, input: R, output: &mut W) -> io::Result<()>
+        where I: Integer + Clone + FromStr + Display, R: BufRead, W: Write {
+    let mut pending = String::new();
+    for line in input.lines() {
         let line = try!(line);
-        program.push_str(&line);
-        match parse::parse(&*program) {
+        pending.push_str(&line);
+        pending.push('\n');
+        match parse::parse(&*pending) {
             Ok(ref p) => match vm.run_block(p) {
-                Ok(()) => println!("{}", vm.stack),
-                Err(e) => println!("runtime error: {}", e),
+                Ok(()) => try!(writeln!(output, "{}", vm.stack)),
+                Err(vm::Error::Quit) => break,
+                Err(e) => try!(writeln!(output, "runtime error: {}", e)),
             },
-            Err(e) => match e {
-                _ if e.is_recoverable() => continue,
-                parse::Error::LexError(e) =>
-                    println!("lexer error: {}", e),
-                _ => println!("parser error: {}", e),
-            }
+            Err(ref e) if e.is_recoverable() => {
+                try!(write!(output, "... "));
+                try!(output.flush());
+                continue;
+            },
+            Err(ref e) => try!(writeln!(output, "{}", describe_parse_error(e))),
         }
-        program.clear();
+        pending.clear();
     }
     Ok(())
 }
 
-fn batch<I>(vm: &mut Vm<I>, filename: &str) -> io::Result<()>
+fn interactive<I>(vm: &mut Vm<I>) -> io::Result<()>
+        where I: Integer + Clone + FromStr + Display {
+    let stdin = stdin();
+    let stdout = stdout();
+    let mut stdout = stdout.lock();
+    repl(vm, stdin.lock(), &mut stdout)
+}
+
+fn batch<I>(vm: &mut Vm<I>, filename: &str) -> io::Result<i32>
         where I: Integer + Clone + FromStr + Display {
     let mut file = try!(File::open(filename));
     let mut program = String::new();
     try!(file.read_to_string(&mut program));
-    match parse::parse(&program) {
+    Ok(eval(vm, &program))
+}
+
+// Run a whole program and print the resulting stack, returning the exit
+// status: 0 if it ran without error, 1 if it failed, or whatever it asked for
+// with `quit` or `exit`.
+fn eval<I>(vm: &mut Vm<I>, program: &str) -> i32
+        where I: Integer + Clone + FromStr + Display {
+    match parse::parse(program) {
         Ok(ref p) => match vm.run_block(p) {
-            Ok(()) => println!("{}", vm.stack),
+            Ok(()) => {
+                println!("{}", vm.stack);
+                return 0;
+            },
+            Err(vm::Error::Quit) => return vm.quit_requested.unwrap_or(0),
             Err(e) => println!("runtime error: {}", e),
         },
-        Err(e) => match e {
-            parse::Error::LexError(e) => println!("lexer error: {}", e),
-            _ => println!("parser error: {}", e),
-        }
+        Err(ref e) => println!("{}", describe_parse_error(e)),
     }
-    Ok(())
+    1
 }
 
-fn main() {
-    let mut vm = Vm::<BigInt>::new();
+// Where the program to run comes from.
+enum Source {
+    File(String),
+    Inline(String),
+    Interactive,
+}
+
+// Create a VM using integer type `I` and run the program from `source`,
+// returning the exit status. Adding an integer backend only needs a new call
+// to this.
+fn run<I>(source: Source) -> io::Result<i32>
+        where I: Integer + Clone + FromStr + Display + Debug + ToPrimitive
+                 + FromPrimitive + CheckedAdd + CheckedSub + CheckedMul
+                 + CheckedDiv {
+    let mut vm = Vm::<I>::new();
     builtin::insert_all(&mut vm);
-    let args = env::args();
-    if let Some(filename) = args.skip(1).next() {
-        batch(&mut vm, &filename).unwrap();
-    } else {
-        interactive(&mut vm).unwrap();
+    builtin::insert_fs(&mut vm);
+    builtin::insert_random(&mut vm);
+    builtin::insert_time(&mut vm);
+    builtin::insert_eval(&mut vm);
+    match source {
+        Source::File(filename) => batch(&mut vm, &filename),
+        Source::Inline(program) => Ok(eval(&mut vm, &program)),
+        Source::Interactive => {
+            try!(interactive(&mut vm));
+            Ok(vm.quit_requested.unwrap_or(0))
+        },
+    }
+}
+
+fn main() {
+    let usage = "usage: rustpn [--int=i64|i128|bigint] [-e program | filename]";
+    let mut int = "bigint".to_string();
+    let mut source = Source::Interactive;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if let Some(name) = arg.strip_prefix("--int=") {
+            int = name.to_string();
+        } else if let Source::Interactive = source {
+            source = if arg == "-e" || arg == "--eval" {
+                match args.next() {
+                    Some(program) => Source::Inline(program),
+                    None => {
+                        println!("{}", usage);
+                        process::exit(2);
+                    },
+                }
+            } else {
+                Source::File(arg)
+            };
+        } else {
+            println!("{}", usage);
+            process::exit(2);
+        }
+    }
+    let status = match &*int {
+        "i64" => run::<i64>(source),
+        "i128" => run::<i128>(source),
+        "bigint" => run::<BigInt>(source),
+        _ => {
+            println!("unknown integer type: {}", int);
+            process::exit(2);
+        },
+    }.unwrap();
+    if status != 0 {
+        process::exit(status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use rustpn::vm::Vm;
+    use rustpn::builtin;
+    use super::repl;
+
+    fn run_repl(input: &str) -> String {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_all(&mut vm);
+        let mut output = Vec::new();
+        repl(&mut vm, Cursor::new(input), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_repl_quit() {
+        assert_eq!(run_repl("1\nquit 2\n3\n"), "1 \n");
+    }
+
+    #[test]
+    fn test_repl_multi_line_block() {
+        assert_eq!(run_repl(":f {\n2 3 +\n} fn f\n"), "... ... 5 \n");
+    }
+
+    #[test]
+    fn test_repl_lines_are_separate_tokens() {
+        assert_eq!(run_repl("1\n2 # comment\n+\n"), "1 \n1 2 \n3 \n");
+    }
+
+    #[test]
+    fn test_repl_clears_after_error() {
+        assert_eq!(run_repl("1 0 /\nclear 2\n"), "runtime error: Divided by zero\n2 \n");
     }
 }
@@ -1,139 +1,692 @@
 //! Common builtins.
 
 use std::rc::Rc;
+use std::cell::Cell;
+use std::cmp;
+use std::f64;
+use std::fs;
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
 use std::string::ToString;
-use item::StackItem;
-use vm::{Vm, Error, Method};
-use num::{zero, one, Integer, ToPrimitive, FromPrimitive};
-
-pub fn insert_arithmetic<I>(vm: &mut Vm<I>) where I: Integer + Clone {
-    vm.insert_builtin("+", Box::new(|vm| {
-        let n2 = try!(vm.stack.pop());
-        let n1 = try!(vm.stack.pop());
-        match (n2, n1) {
-            (StackItem::Integer(n2), StackItem::Integer(n1))
-                => vm.stack.push(StackItem::Integer(n1 + n2)),
-            (StackItem::Float(n2), StackItem::Float(n1))
-                => vm.stack.push(StackItem::Float(n1 + n2)),
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use item::{Block, BlockItem, StackItem};
+use parse::parse;
+use vm::{Vm, Error, Method, StackEffect};
+use num::{zero, one, Integer, Num, ToPrimitive, FromPrimitive};
+use num::{CheckedAdd, CheckedSub, CheckedMul, CheckedDiv, Zero};
+use num::bigint::BigInt;
+use num::rational::BigRational;
+
+// Pop the two operands of an arithmetic builtin, bottom first. If `auto_promote`
+// is set and one is an integer while the other is a float, the integer is
+// converted to a float.
+fn pop_operands<I>(vm: &mut Vm<I>) -> Result<(StackItem<I>, StackItem<I>), Error>
+        where I: Clone + ToPrimitive {
+    let (n1, n2) = try!(vm.stack.pop2());
+    if !vm.auto_promote {
+        return Ok((n1, n2));
+    }
+    let to_float = |n: I| n.to_f64().map(StackItem::Float).ok_or(Error::NumericConversion);
+    Ok(match (n1, n2) {
+        (StackItem::Integer(n1), n2 @ StackItem::Float(_)) => (try!(to_float(n1)), n2),
+        (n1 @ StackItem::Float(_), StackItem::Integer(n2)) => (n1, try!(to_float(n2))),
+        operands => operands,
+    })
+}
+
+// Convert an integer exactly, going through its decimal form since not every
+// integer type converts to `BigInt` directly.
+fn integer_to_rational<I>(n: &I) -> Option<BigRational> where I: ToString {
+    n.to_string().parse::<BigInt>().ok().map(BigRational::from_integer)
+}
+
+fn as_rational<I>(item: &StackItem<I>) -> Option<BigRational> where I: ToString {
+    match *item {
+        StackItem::Integer(ref n) => integer_to_rational(n),
+        StackItem::Rational(ref r) => Some(r.clone()),
+        _ => None,
+    }
+}
+
+// Push `f` applied to a pair of operands where one is a rational and the
+// other is a rational or an integer, which is promoted. On error the operands
+// are pushed back instead.
+fn rational_op<I, F>(vm: &mut Vm<I>, n1: StackItem<I>, n2: StackItem<I>, f: F)
+        -> Result<(), Error>
+        where I: ToString,
+              F: FnOnce(BigRational, BigRational) -> Result<BigRational, Error> {
+    let result = match (&n1, &n2) {
+        (StackItem::Rational(_), _) | (_, StackItem::Rational(_)) =>
+            match (as_rational(&n1), as_rational(&n2)) {
+                (Some(a), Some(b)) => f(a, b),
+                _ => Err(Error::TypeError),
+            },
+        _ => Err(Error::TypeError),
+    };
+    match result {
+        Ok(r) => vm.stack.push(StackItem::Rational(r)),
+        Err(e) => {
+            try!(vm.stack.push(n1));
+            try!(vm.stack.push(n2));
+            Err(e)
+        },
+    }
+}
+
+// Promote three operands to rationals when at least one is a rational and the
+// rest are rationals or integers.
+fn rational_triple<I>(a: &StackItem<I>, b: &StackItem<I>, c: &StackItem<I>)
+        -> Option<(BigRational, BigRational, BigRational)> where I: ToString {
+    match (a, b, c) {
+        (StackItem::Rational(_), _, _) | (_, StackItem::Rational(_), _)
+            | (_, _, StackItem::Rational(_)) => {},
+        _ => return None,
+    }
+    match (as_rational(a), as_rational(b), as_rational(c)) {
+        (Some(a), Some(b), Some(c)) => Some((a, b, c)),
+        _ => None,
+    }
+}
+
+// Equality as used by `eq`: like `==`, except that an integer equals a
+// rational of the same value.
+fn items_equal<I>(a: &StackItem<I>, b: &StackItem<I>) -> bool
+        where I: PartialEq + ToString {
+    match (a, b) {
+        (StackItem::Integer(_), StackItem::Rational(_))
+            | (StackItem::Rational(_), StackItem::Integer(_)) =>
+            as_rational(a) == as_rational(b),
+        _ => a == b,
+    }
+}
+
+// Equality as used by `deep-eq`: like `items_equal` at every level of lists
+// and blocks, except that NaN equals NaN.
+fn deep_equal<I>(a: &StackItem<I>, b: &StackItem<I>) -> bool
+        where I: PartialEq + ToString {
+    match (a, b) {
+        (StackItem::Float(a), StackItem::Float(b)) => a == b || (a.is_nan() && b.is_nan()),
+        (StackItem::List(a), StackItem::List(b)) =>
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| deep_equal(a, b)),
+        (StackItem::Block(a), StackItem::Block(b)) => blocks_deep_equal(a, b),
+        _ => items_equal(a, b),
+    }
+}
+
+fn blocks_deep_equal<I>(a: &Block<I>, b: &Block<I>) -> bool where I: PartialEq + ToString {
+    a.0.len() == b.0.len() && a.0.iter().zip(&b.0).all(|pair| match pair {
+        (BlockItem::Literal(a), BlockItem::Literal(b)) => deep_equal(a, b),
+        (BlockItem::List(a), BlockItem::List(b)) => blocks_deep_equal(a, b),
+        (a, b) => a == b,
+    })
+}
+
+// Define a builtin adding one to a number, or subtracting one unless `up`.
+fn insert_step<I>(vm: &mut Vm<I>, name: &str, up: bool)
+        where I: Integer + Clone + CheckedAdd + CheckedSub {
+    vm.insert_builtin_with_signature(name, "( n -- n )", Box::new(move |vm| {
+        let stepped = match *try!(vm.stack.peek()) {
+            StackItem::Integer(ref n) => {
+                let n = if up { n.checked_add(&one()) } else { n.checked_sub(&one()) };
+                StackItem::Integer(try!(n.ok_or(Error::IntegerOverflow)))
+            },
+            StackItem::Float(f) => StackItem::Float(if up { f + 1.0 } else { f - 1.0 }),
+            StackItem::Rational(ref r) => {
+                let step = one::<BigRational>();
+                StackItem::Rational(if up { r + step } else { r - step })
+            },
             _ => return Err(Error::TypeError),
+        };
+        try!(vm.stack.pop());
+        vm.stack.push(stepped)
+    }));
+}
+
+pub fn insert_arithmetic<I>(vm: &mut Vm<I>)
+        where I: Integer + Clone + ToPrimitive + ToString + CheckedAdd + CheckedSub
+                 + CheckedMul + CheckedDiv {
+    insert_step(vm, "inc", true);
+    insert_step(vm, "dec", false);
+    vm.insert_builtin_with_signature("+", "( n n -- n )", Box::new(|vm| {
+        let (n1, n2) = try!(pop_operands(vm));
+        match (n1, n2) {
+            (StackItem::Integer(n1), StackItem::Integer(n2)) => match n1.checked_add(&n2) {
+                Some(n) => try!(vm.stack.push(StackItem::Integer(n))),
+                None => return Err(Error::IntegerOverflow),
+            },
+            (StackItem::Float(n1), StackItem::Float(n2))
+                => try!(vm.stack.push(StackItem::Float(n1 + n2))),
+            (n1, n2) => try!(rational_op(vm, n1, n2, |a, b| Ok(a + b))),
         }
         Ok(())
     }));
-    vm.insert_builtin("-", Box::new(|vm| {
-        let n2 = try!(vm.stack.pop());
-        let n1 = try!(vm.stack.pop());
-        match (n2, n1) {
-            (StackItem::Integer(n2), StackItem::Integer(n1))
-                => vm.stack.push(StackItem::Integer(n1 - n2)),
-            (StackItem::Float(n2), StackItem::Float(n1))
-                => vm.stack.push(StackItem::Float(n1 - n2)),
-            _ => return Err(Error::TypeError),
+    vm.insert_builtin_with_signature("-", "( n n -- n )", Box::new(|vm| {
+        let (n1, n2) = try!(pop_operands(vm));
+        match (n1, n2) {
+            (StackItem::Integer(n1), StackItem::Integer(n2)) => match n1.checked_sub(&n2) {
+                Some(n) => try!(vm.stack.push(StackItem::Integer(n))),
+                None => return Err(Error::IntegerOverflow),
+            },
+            (StackItem::Float(n1), StackItem::Float(n2))
+                => try!(vm.stack.push(StackItem::Float(n1 - n2))),
+            (n1, n2) => try!(rational_op(vm, n1, n2, |a, b| Ok(a - b))),
         }
         Ok(())
     }));
-    vm.insert_builtin("*", Box::new(|vm| {
-        let n2 = try!(vm.stack.pop());
-        let n1 = try!(vm.stack.pop());
-        match (n2, n1) {
-            (StackItem::Integer(n2), StackItem::Integer(n1))
-                => vm.stack.push(StackItem::Integer(n1 * n2)),
-            (StackItem::Float(n2), StackItem::Float(n1))
-                => vm.stack.push(StackItem::Float(n1 * n2)),
-            _ => return Err(Error::TypeError),
+    vm.insert_builtin_with_signature("*", "( n n -- n )", Box::new(|vm| {
+        let (n1, n2) = try!(pop_operands(vm));
+        match (n1, n2) {
+            (StackItem::Integer(n1), StackItem::Integer(n2)) => match n1.checked_mul(&n2) {
+                Some(n) => try!(vm.stack.push(StackItem::Integer(n))),
+                None => return Err(Error::IntegerOverflow),
+            },
+            (StackItem::Float(n1), StackItem::Float(n2))
+                => try!(vm.stack.push(StackItem::Float(n1 * n2))),
+            (n1, n2) => try!(rational_op(vm, n1, n2, |a, b| Ok(a * b))),
         }
         Ok(())
     }));
-    vm.insert_builtin("/", Box::new(|vm| {
-        let n2 = try!(vm.stack.pop());
-        let n1 = try!(vm.stack.pop());
-        match (n2, n1) {
-            (StackItem::Integer(n2), StackItem::Integer(n1)) => if n2 == zero() {
+    vm.insert_builtin_with_signature("/", "( n n -- n )", Box::new(|vm| {
+        let (n1, n2) = try!(pop_operands(vm));
+        match (n1, n2) {
+            (StackItem::Integer(n1), StackItem::Integer(n2)) => if n2 == zero() {
                     return Err(Error::DivideByZero);
                 } else {
-                    vm.stack.push(StackItem::Integer(n1 / n2))
+                    match n1.checked_div(&n2) {
+                        Some(n) => try!(vm.stack.push(StackItem::Integer(n))),
+                        None => return Err(Error::IntegerOverflow),
+                    }
                 },
-            (StackItem::Float(n2), StackItem::Float(n1))
-                => vm.stack.push(StackItem::Float(n1 / n2)),
-            _ => return Err(Error::TypeError),
+            (StackItem::Float(n1), StackItem::Float(n2))
+                => try!(vm.stack.push(StackItem::Float(n1 / n2))),
+            (n1, n2) => try!(rational_op(vm, n1, n2, |a, b| {
+                if b.is_zero() { Err(Error::DivideByZero) } else { Ok(a / b) }
+            })),
         }
         Ok(())
     }));
+    vm.insert_builtin_with_signature("gcd", "( i i -- i )", Box::new(|vm| integer_op(vm, gcd)));
+    vm.insert_builtin_with_signature("lcm", "( i i -- i )", Box::new(|vm| integer_op(vm, lcm)));
 }
 
-pub fn insert_conversions<I>(vm: &mut Vm<I>)
-        where I: Integer + Clone + FromPrimitive + ToPrimitive + ToString {
-    vm.insert_builtin("as-integer", Box::new(|vm| {
-        let n = try!(vm.stack.pop());
-        vm.stack.push(match n {
-            i @ StackItem::Integer(_) => i,
-            StackItem::Float(f) => {
-                let i = try!(FromPrimitive::from_f64(f).ok_or(Error::NumericConversion));
-                StackItem::Integer(i)
+// Replace two integers with `f` applied to them.
+fn integer_op<I>(vm: &mut Vm<I>, f: fn(I, I) -> Result<I, Error>) -> Result<(), Error>
+        where I: Clone {
+    match try!(vm.stack.pop2()) {
+        (StackItem::Integer(a), StackItem::Integer(b)) => {
+            let result = try!(f(a, b));
+            vm.stack.push(StackItem::Integer(result))
+        },
+        (a, b) => {
+            try!(vm.stack.push(a));
+            try!(vm.stack.push(b));
+            Err(Error::TypeError)
+        },
+    }
+}
+
+// Absolute value, or `IntegerOverflow` for a minimum such as `i64::MIN`.
+fn checked_abs<I>(n: I) -> Result<I, Error> where I: Integer + CheckedSub {
+    if n < zero() {
+        zero::<I>().checked_sub(&n).ok_or(Error::IntegerOverflow)
+    } else {
+        Ok(n)
+    }
+}
+
+// Greatest common divisor, never negative; the gcd of zero and n is |n|.
+fn gcd<I>(a: I, b: I) -> Result<I, Error>
+        where I: Integer + Clone + CheckedSub + CheckedDiv {
+    let (mut a, mut b) = (a, b);
+    while !b.is_zero() {
+        // Only a minimum divided by -1 overflows, and that leaves no
+        // remainder.
+        let r = match a.checked_div(&b) {
+            Some(q) => a - q * b.clone(),
+            None => zero(),
+        };
+        a = b;
+        b = r;
+    }
+    checked_abs(a)
+}
+
+// Least common multiple, never negative; zero if either is zero.
+fn lcm<I>(a: I, b: I) -> Result<I, Error>
+        where I: Integer + Clone + CheckedSub + CheckedMul + CheckedDiv {
+    if a.is_zero() || b.is_zero() {
+        return Ok(zero());
+    }
+    let g = try!(gcd(a.clone(), b.clone()));
+    let l = try!((a / g).checked_mul(&b).ok_or(Error::IntegerOverflow));
+    checked_abs(l)
+}
+
+// Insert a builtin replacing a float on top of the stack with `f` applied to
+// it. Integers are rejected rather than promoted; use `as-float` first.
+fn insert_float_fn<I>(vm: &mut Vm<I>, name: &str, f: fn(f64) -> f64)
+        where I: Clone {
+    vm.insert_builtin_with_signature(name, "( f -- f )", Box::new(move |vm| {
+        match try!(vm.stack.pop()) {
+            StackItem::Float(n) => try!(vm.stack.push(StackItem::Float(f(n)))),
+            other => {
+                try!(vm.stack.push(other));
+                return Err(Error::TypeError);
             },
-            _ => return Err(Error::TypeError),
-        });
+        }
+        Ok(())
+    }));
+}
+
+// Insert a builtin pushing a constant float.
+fn insert_float_const<I>(vm: &mut Vm<I>, name: &str, value: f64)
+        where I: Clone {
+    vm.insert_builtin_with_signature(name, "( -- f )", Box::new(move |vm| {
+        try!(vm.stack.push(StackItem::Float(value)));
         Ok(())
     }));
-    vm.insert_builtin("as-float", Box::new(|vm| {
+}
+
+/// Rounding and square roots of floats. `sqrt` of a negative number is NaN.
+pub fn insert_float_math<I>(vm: &mut Vm<I>) where I: Integer + Clone {
+    insert_float_fn(vm, "floor", f64::floor);
+    insert_float_fn(vm, "ceil", f64::ceil);
+    insert_float_fn(vm, "round", f64::round);
+    insert_float_fn(vm, "sqrt", f64::sqrt);
+}
+
+/// Trigonometric, logarithmic and exponential functions of floats, plus the
+/// constants `pi`, `e`, `inf` and `nan`. Domain errors such as `ln` of a
+/// negative number give NaN, as with `f64`.
+pub fn insert_transcendental<I>(vm: &mut Vm<I>) where I: Integer + Clone {
+    insert_float_fn(vm, "sin", f64::sin);
+    insert_float_fn(vm, "cos", f64::cos);
+    insert_float_fn(vm, "tan", f64::tan);
+    insert_float_fn(vm, "ln", f64::ln);
+    insert_float_fn(vm, "log10", f64::log10);
+    insert_float_fn(vm, "exp", f64::exp);
+    insert_float_const(vm, "pi", f64::consts::PI);
+    insert_float_const(vm, "e", f64::consts::E);
+    insert_float_const(vm, "inf", f64::INFINITY);
+    insert_float_const(vm, "nan", f64::NAN);
+}
+
+// Define a predicate testing whether a number compares to zero as `wanted`.
+// NaN satisfies none of them.
+fn insert_sign_predicate<I>(vm: &mut Vm<I>, name: &str, wanted: cmp::Ordering)
+        where I: Integer + Clone {
+    vm.insert_builtin_with_signature(name, "( n -- b )", Box::new(move |vm| {
+        let ordering = match try!(vm.stack.pop()) {
+            StackItem::Integer(n) => n.partial_cmp(&zero()),
+            StackItem::Float(f) => f.partial_cmp(&0.0),
+            StackItem::Rational(r) => r.partial_cmp(&BigRational::zero()),
+            other => {
+                try!(vm.stack.push(other));
+                return Err(Error::TypeError);
+            },
+        };
+        vm.stack.push(StackItem::Boolean(ordering == Some(wanted)))
+    }));
+}
+
+pub fn insert_comparison<I>(vm: &mut Vm<I>) where I: Integer + Clone + ToString {
+    insert_sign_predicate(vm, "zero?", cmp::Ordering::Equal);
+    insert_sign_predicate(vm, "positive?", cmp::Ordering::Greater);
+    insert_sign_predicate(vm, "negative?", cmp::Ordering::Less);
+    vm.insert_builtin_with_signature("min", "( n n -- n )", Box::new(|vm| {
+        let (a, b) = try!(vm.stack.pop2());
+        match (a, b) {
+            (StackItem::Integer(a), StackItem::Integer(b))
+                => try!(vm.stack.push(StackItem::Integer(cmp::min(a, b)))),
+            (StackItem::Float(a), StackItem::Float(b))
+                => try!(vm.stack.push(StackItem::Float(a.min(b)))),
+            (a, b) => try!(rational_op(vm, a, b, |a, b| Ok(cmp::min(a, b)))),
+        }
+        Ok(())
+    }));
+    vm.insert_builtin_with_signature("max", "( n n -- n )", Box::new(|vm| {
+        let (a, b) = try!(vm.stack.pop2());
+        match (a, b) {
+            (StackItem::Integer(a), StackItem::Integer(b))
+                => try!(vm.stack.push(StackItem::Integer(cmp::max(a, b)))),
+            (StackItem::Float(a), StackItem::Float(b))
+                => try!(vm.stack.push(StackItem::Float(a.max(b)))),
+            (a, b) => try!(rational_op(vm, a, b, |a, b| Ok(cmp::max(a, b)))),
+        }
+        Ok(())
+    }));
+    vm.insert_builtin_with_signature("clamp", "( n n n -- n )", Box::new(|vm| {
+        let (value, low, high) = try!(vm.stack.pop3());
+        let clamped = match (&value, &low, &high) {
+            (StackItem::Integer(v), StackItem::Integer(l), StackItem::Integer(h))
+                => Ok(clamp(v.clone(), l.clone(), h.clone()).map(StackItem::Integer)),
+            (StackItem::Float(v), StackItem::Float(l), StackItem::Float(h))
+                => Ok(clamp(*v, *l, *h).map(StackItem::Float)),
+            _ => rational_triple(&value, &low, &high)
+                .map(|(v, l, h)| clamp(v, l, h).map(StackItem::Rational))
+                .ok_or(Error::TypeError),
+        };
+        let err = match clamped {
+            Ok(Some(n)) => return vm.stack.push(n),
+            Ok(None) => Error::OutOfBounds,
+            Err(e) => e,
+        };
+        try!(vm.stack.push(value));
+        try!(vm.stack.push(low));
+        try!(vm.stack.push(high));
+        Err(err)
+    }));
+    // Whether `low <= value <= high`; reversed bounds are an error, as for
+    // `clamp`.
+    vm.insert_builtin_with_signature("between?", "( n n n -- b )", Box::new(|vm| {
+        let (value, low, high) = try!(vm.stack.pop3());
+        let within = match (&value, &low, &high) {
+            (StackItem::Integer(v), StackItem::Integer(l), StackItem::Integer(h))
+                => Ok(clamp(v, l, h).map(|c| c == v)),
+            (StackItem::Float(v), StackItem::Float(l), StackItem::Float(h))
+                => Ok(clamp(v, l, h).map(|c| c == v)),
+            _ => rational_triple(&value, &low, &high)
+                .map(|(v, l, h)| clamp(&v, &l, &h).map(|c| *c == v))
+                .ok_or(Error::TypeError),
+        };
+        let err = match within {
+            Ok(Some(within)) => return vm.stack.push(StackItem::Boolean(within)),
+            Ok(None) => Error::OutOfBounds,
+            Err(e) => e,
+        };
+        try!(vm.stack.push(value));
+        try!(vm.stack.push(low));
+        try!(vm.stack.push(high));
+        Err(err)
+    }));
+}
+
+// Constrain `value` to `[low, high]`, or `None` if the bounds are reversed
+// (or, for floats, not comparable).
+fn clamp<T>(value: T, low: T, high: T) -> Option<T> where T: PartialOrd {
+    match low.partial_cmp(&high) {
+        Some(cmp::Ordering::Greater) | None => return None,
+        _ => {},
+    }
+    if value < low {
+        Some(low)
+    } else if value > high {
+        Some(high)
+    } else {
+        Some(value)
+    }
+}
+
+pub fn insert_conversions<I>(vm: &mut Vm<I>)
+        where I: Integer + Clone + FromPrimitive + ToPrimitive + Display + Debug {
+    vm.insert_builtin_with_signature("as-integer", "( n -- i )", Box::new(|vm| {
         let n = try!(vm.stack.pop());
-        vm.stack.push(match n {
-            StackItem::Integer(n) => {
-                let f = try!(n.to_f64().ok_or(Error::NumericConversion));
-                StackItem::Float(f)
+        let converted = match n {
+            StackItem::Integer(_) => return vm.stack.push(n),
+            StackItem::Float(f) => FromPrimitive::from_f64(f).ok_or(Error::NumericConversion),
+            // Truncates toward zero, as for floats.
+            StackItem::Rational(ref r) => I::from_str_radix(&r.to_integer().to_string(), 10)
+                .map_err(|_| Error::NumericConversion),
+            _ => Err(Error::TypeError),
+        };
+        match converted {
+            Ok(i) => vm.stack.push(StackItem::Integer(i)),
+            Err(e) => {
+                try!(vm.stack.push(n));
+                Err(e)
+            },
+        }
+    }));
+    vm.insert_builtin_with_signature("as-float", "( n -- f )", Box::new(|vm| {
+        let f = match *try!(vm.stack.peek()) {
+            StackItem::Integer(ref n) => try!(n.to_f64().ok_or(Error::NumericConversion)),
+            StackItem::Float(f) => f,
+            StackItem::Rational(ref r) => match (r.numer().to_f64(), r.denom().to_f64()) {
+                (Some(numer), Some(denom)) => numer / denom,
+                _ => return Err(Error::NumericConversion),
             },
-            f @ StackItem::Float(_) => f,
             _ => return Err(Error::TypeError),
-        });
-        Ok(())
+        };
+        try!(vm.stack.pop());
+        vm.stack.push(StackItem::Float(f))
+    }));
+    // Floats convert exactly, so `0.1` gives the nearest binary fraction
+    // rather than 1/10.
+    vm.insert_builtin_with_signature("as-rational", "( n -- r )", Box::new(|vm| {
+        let r = match try!(vm.stack.pop()) {
+            StackItem::Integer(ref i) => integer_to_rational(i),
+            StackItem::Float(f) => BigRational::from_float(f),
+            StackItem::Rational(r) => Some(r),
+            other => {
+                try!(vm.stack.push(other));
+                return Err(Error::TypeError);
+            },
+        };
+        let r = try!(r.ok_or(Error::NumericConversion));
+        vm.stack.push(StackItem::Rational(r))
     }));
-    vm.insert_builtin("to-string", Box::new(|vm| {
+    vm.insert_builtin_with_signature("make-rational", "( i i -- r )", Box::new(|vm| {
+        let (numer, denom) = try!(vm.stack.pop2());
+        let (numer, denom) = match (numer, denom) {
+            (StackItem::Integer(numer), StackItem::Integer(denom)) => (numer, denom),
+            (numer, denom) => {
+                try!(vm.stack.push(numer));
+                try!(vm.stack.push(denom));
+                return Err(Error::TypeError);
+            },
+        };
+        if denom == zero() {
+            try!(vm.stack.push(StackItem::Integer(numer)));
+            try!(vm.stack.push(StackItem::Integer(denom)));
+            return Err(Error::DivideByZero);
+        }
+        match (integer_to_rational(&numer), integer_to_rational(&denom)) {
+            (Some(numer), Some(denom)) => vm.stack.push(StackItem::Rational(numer / denom)),
+            _ => Err(Error::NumericConversion),
+        }
+    }));
+    vm.insert_builtin_with_signature("char->int", "( c -- i )", Box::new(|vm| {
+        let c = try!(vm.stack.pop_char());
+        let i = try!(FromPrimitive::from_u32(c as u32).ok_or(Error::NumericConversion));
+        vm.stack.push(StackItem::Integer(i))
+    }));
+    vm.insert_builtin_with_signature("int->char", "( i -- c )", Box::new(|vm| {
+        let i = try!(vm.stack.pop_integer());
+        match i.to_u32().and_then(char::from_u32) {
+            Some(c) => vm.stack.push(StackItem::Char(c)),
+            None => {
+                try!(vm.stack.push(StackItem::Integer(i)));
+                Err(Error::NumericConversion)
+            },
+        }
+    }));
+    vm.insert_builtin_with_signature("to-string", "( a -- s )", Box::new(|vm| {
         let a = try!(vm.stack.pop());
-        vm.stack.push(match a {
+        try!(vm.stack.push(match a {
             s @ StackItem::String(_) => s,
+            StackItem::Char(c) => StackItem::String(c.to_string()),
             StackItem::Integer(i) => StackItem::String(i.to_string()),
-            StackItem::Float(f) => StackItem::String(f.to_string()),
+            f @ StackItem::Float(_) => StackItem::String(f.to_string()),
+            StackItem::Rational(r) =>
+                StackItem::String(format!("{}/{}", r.numer(), r.denom())),
             _ => return Err(Error::TypeError),
-        });
+        }));
+        Ok(())
+    }));
+    vm.insert_builtin_with_signature("repr", "( a -- s )", Box::new(|vm| {
+        let a = try!(vm.stack.pop());
+        try!(vm.stack.push(StackItem::String(format!("{:?}", a))));
         Ok(())
     }));
+    vm.insert_builtin_with_signature("parse-int", "( s i -- i )", Box::new(|vm| {
+        let (s, base) = try!(vm.stack.pop2());
+        let parsed = match (&s, &base) {
+            (StackItem::String(s), StackItem::Integer(base)) => match base.to_u32() {
+                Some(base) if (2..=36).contains(&base) =>
+                    <I as Num>::from_str_radix(s, base).map_err(|_| Error::NumericConversion),
+                _ => Err(Error::OutOfBounds),
+            },
+            _ => Err(Error::TypeError),
+        };
+        match parsed {
+            Ok(i) => vm.stack.push(StackItem::Integer(i)),
+            Err(e) => {
+                try!(vm.stack.push(s));
+                try!(vm.stack.push(base));
+                Err(e)
+            },
+        }
+    }));
+    vm.insert_builtin_with_signature("parse-float", "( s -- f )", Box::new(|vm| {
+        let f = match *try!(vm.stack.peek()) {
+            StackItem::String(ref s) => try!(s.parse().map_err(|_| Error::NumericConversion)),
+            _ => return Err(Error::TypeError),
+        };
+        try!(vm.stack.pop());
+        vm.stack.push(StackItem::Float(f))
+    }));
+    vm.insert_builtin_with_signature("type-of", "( a -- s )", Box::new(|vm| {
+        let a = try!(vm.stack.pop());
+        try!(vm.stack.push(StackItem::String(a.type_name().to_string())));
+        Ok(())
+    }));
+}
+
+// Define a method. Unless `force` is set, builtins may not be redefined.
+fn define_method<I>(vm: &mut Vm<I>, name: String, method: Method<I>, force: bool)
+        -> Result<(), Error> where I: Clone {
+    if vm.protected.contains(&name) {
+        return Err(Error::Protected(name));
+    }
+    if !force && vm.is_builtin(&name) {
+        return Err(Error::RedefineBuiltin(name));
+    }
+    vm.methods.insert(name, Rc::new(method));
+    Ok(())
+}
+
+// Define a method made by `method` from a popped name and block, pushing both
+// back if that fails.
+fn define_popped<I, F>(vm: &mut Vm<I>, name: StackItem<I>, block: StackItem<I>,
+                       force: bool, method: F) -> Result<(), Error>
+        where I: Clone, F: FnOnce(Rc<Block<I>>) -> Method<I> {
+    let result = match (&name, &block) {
+        (StackItem::Symbol(s), StackItem::Block(b)) =>
+            define_method(vm, s.clone(), method(b.clone()), force),
+        _ => Err(Error::TypeError),
+    };
+    if result.is_err() {
+        try!(vm.stack.push(name));
+        try!(vm.stack.push(block));
+    }
+    result
+}
+
+// Define a method from a block, as for `fn` and `fn!`.
+fn define<I>(vm: &mut Vm<I>, force: bool) -> Result<(), Error> where I: Clone {
+    let (name, block) = try!(vm.stack.pop2());
+    define_popped(vm, name, block, force, Method::Block)
 }
 
 pub fn insert_fn<I>(vm: &mut Vm<I>) where I: Integer + Clone {
-    vm.insert_builtin("fn", Box::new(|vm| {
-        let block = try!(vm.stack.pop());
-        let name = try!(vm.stack.pop());
-        match (name, block) {
-            (StackItem::Symbol(s), StackItem::Block(b)) =>
-                { vm.methods.insert(s, Rc::new(Method::Block(b))); },
-            _ => return Err(Error::TypeError),
+    vm.insert_builtin_with_signature("fn", "( sym block -- )", Box::new(|vm| define(vm, false)));
+    // Like `fn`, but may also redefine builtins.
+    vm.insert_builtin_with_signature("fn!", "( sym block -- )", Box::new(|vm| define(vm, true)));
+    // Like `fn`, with a stack effect such as "( a b -- c )" which is checked
+    // each time the method returns.
+    vm.insert_builtin_with_signature("fn-effect", "( sym block s -- )", Box::new(|vm| {
+        let effect = try!(vm.stack.pop_string());
+        let parsed = match StackEffect::parse(&effect) {
+            Some(parsed) => parsed,
+            None => {
+                try!(vm.stack.push(StackItem::String(effect.clone())));
+                return Err(Error::ParseError(effect));
+            },
+        };
+        let (name, block) = match vm.stack.pop2() {
+            Ok(operands) => operands,
+            Err(e) => {
+                try!(vm.stack.push(StackItem::String(effect)));
+                return Err(e);
+            },
+        };
+        let result = define_popped(vm, name, block, false, |b| Method::CheckedBlock(b, parsed));
+        if result.is_err() {
+            try!(vm.stack.push(StackItem::String(effect)));
         }
+        result
+    }));
+    // Bind values to names for the rest of the current method, e.g.
+    // `[:x :y] locals` binds `x` to the item below `y`. Calling a local's
+    // name pushes its value, in preference to any method of that name.
+    vm.insert_builtin_with_signature("locals", "( ... list -- )", Box::new(|vm| {
+        let names = match try!(vm.stack.pop()) {
+            StackItem::List(ref l) if l.iter().all(|i| matches!(*i, StackItem::Symbol(_))) =>
+                l.clone(),
+            other => {
+                try!(vm.stack.push(other));
+                return Err(Error::TypeError);
+            },
+        };
+        let len = vm.stack.0.len();
+        if len < names.len() {
+            try!(vm.stack.push(StackItem::List(names)));
+            return Err(Error::StackUnderflow);
+        }
+        let values = vm.stack.split_off(len - names.len());
+        for (name, value) in names.into_iter().zip(values) {
+            if let StackItem::Symbol(name) = name {
+                vm.bind_local(name, value);
+            }
+        }
+        Ok(())
+    }));
+    // Call the method a symbol names, so scripts can pick one at runtime.
+    vm.insert_builtin_with_signature("execute-symbol", "( sym -- ... )", Box::new(|vm| {
+        let name = try!(vm.stack.pop_symbol());
+        vm.call_named(&name)
+    }));
+    // List the names of every defined method, sorted.
+    vm.insert_builtin_with_signature("words", "( -- list )", Box::new(|vm| {
+        let mut names: Vec<_> = vm.methods.keys().cloned().collect();
+        names.sort();
+        let words = names.into_iter().map(StackItem::String).collect();
+        try!(vm.stack.push(StackItem::List(words)));
+        Ok(())
+    }));
+    // Stop a method from being redefined by `fn`.
+    vm.insert_builtin_with_signature("protect", "( sym -- )", Box::new(|vm| {
+        let name = try!(vm.stack.pop_symbol());
+        vm.protected.insert(name);
         Ok(())
     }));
 }
 
 pub fn insert_stack_ops<I>(vm: &mut Vm<I>)
         where I: Integer + Clone + ToPrimitive + FromPrimitive {
-    vm.insert_builtin("swap", Box::new(|vm| {
-        let b = try!(vm.stack.pop());
-        let a = try!(vm.stack.pop());
-        vm.stack.push(b);
-        vm.stack.push(a);
+    vm.insert_builtin_with_signature("swap", "( a b -- b a )", Box::new(|vm| {
+        let (a, b) = try!(vm.stack.pop2());
+        try!(vm.stack.push(b));
+        try!(vm.stack.push(a));
         Ok(())
     }));
-    vm.insert_builtin("clone", Box::new(|vm| {
+    // Blocks are shared rather than copied, so cloning one is cheap however
+    // large it is. Lists and strings are copied.
+    vm.insert_builtin_with_signature("clone", "( a -- a a )", Box::new(|vm| {
         let a = try!(vm.stack.pop());
-        vm.stack.push(a.clone());
-        vm.stack.push(a);
+        try!(vm.stack.push(a.clone()));
+        try!(vm.stack.push(a));
         Ok(())
     }));
-    vm.insert_builtin("clone-nth", Box::new(|vm| {
+    vm.insert_builtin_with_signature("clone-nth", "( ... n -- ... a )", Box::new(|vm| {
         if let StackItem::Integer(n) = try!(vm.stack.pop()) {
             if let Some(n) = n.to_usize() {
                 if n <= vm.stack.0.len() {
                     let idx = vm.stack.0.len() - n;
                     let nth = vm.stack.0.get(idx).map(|i| i.clone());
                     if let Some(nth) = nth {
-                        vm.stack.push(nth);
+                        try!(vm.stack.push(nth));
                     } else {
                         return Err(Error::OutOfBounds);
                     }
@@ -148,68 +701,175 @@ pub fn insert_stack_ops<I>(vm: &mut Vm<I>)
         }
         Ok(())
     }));
-    vm.insert_builtin("clear", Box::new(|vm| {
-        vm.stack.0.clear();
+    // Unlike `clone-nth`, the index counts from 0 at the top of the stack.
+    vm.insert_builtin_with_signature("keep-nth", "( ... n -- a )", Box::new(|vm| {
+        let n = try!(vm.stack.pop_integer());
+        let err = match n.to_usize() {
+            Some(idx) if idx < vm.stack.0.len() => {
+                let idx = vm.stack.0.len() - 1 - idx;
+                let nth = vm.stack.split_off(idx).swap_remove(0);
+                vm.stack.clear();
+                return vm.stack.push(nth);
+            },
+            Some(_) => Error::OutOfBounds,
+            None => Error::IntegerOverflow,
+        };
+        try!(vm.stack.push(StackItem::Integer(n)));
+        Err(err)
+    }));
+    // Swap the top item with the one n-deep, counting from 0 at the top.
+    vm.insert_builtin_with_signature("swap-nth", "( ... n -- ... )", Box::new(|vm| {
+        let n = try!(vm.stack.pop_integer());
+        let len = vm.stack.0.len();
+        let err = match n.to_usize() {
+            Some(idx) if idx < len => {
+                vm.stack.0.swap(len - 1, len - 1 - idx);
+                return Ok(());
+            },
+            Some(_) => Error::OutOfBounds,
+            None => Error::IntegerOverflow,
+        };
+        try!(vm.stack.push(StackItem::Integer(n)));
+        Err(err)
+    }));
+    // Replace the item n-deep, counting from 0 at the top once the value and
+    // index are popped.
+    vm.insert_builtin_with_signature("set-nth", "( ... a n -- ... )", Box::new(|vm| {
+        let n = try!(vm.stack.pop_integer());
+        let value = match vm.stack.pop() {
+            Ok(value) => value,
+            Err(e) => {
+                try!(vm.stack.push(StackItem::Integer(n)));
+                return Err(e);
+            },
+        };
+        let len = vm.stack.0.len();
+        let err = match n.to_usize() {
+            Some(idx) if idx < len => {
+                vm.stack.replace(len - 1 - idx, value);
+                return Ok(());
+            },
+            Some(_) => Error::OutOfBounds,
+            None => Error::IntegerOverflow,
+        };
+        try!(vm.stack.push(value));
+        try!(vm.stack.push(StackItem::Integer(n)));
+        Err(err)
+    }));
+    // Fail unless exactly n items are left once n is popped.
+    vm.insert_builtin_with_signature("expect-depth", "( ... n -- ... )", Box::new(|vm| {
+        let n = try!(vm.stack.pop_integer());
+        let result = n.to_usize().ok_or(Error::IntegerOverflow)
+            .and_then(|expected| vm.assert_depth(expected));
+        if result.is_err() {
+            try!(vm.stack.push(StackItem::Integer(n)));
+        }
+        result
+    }));
+    vm.insert_builtin_with_signature("rotate", "( ... i -- ... )", Box::new(|vm| {
+        // Work out the shift before popping k, so that it stays on error.
+        let len = vm.stack.0.len().saturating_sub(1);
+        let shift = match *try!(vm.stack.peek()) {
+            StackItem::Integer(_) if len == 0 => 0,
+            StackItem::Integer(ref k) => {
+                let modulus: I = try!(FromPrimitive::from_usize(len)
+                                      .ok_or(Error::IntegerOverflow));
+                try!(k.mod_floor(&modulus).to_usize().ok_or(Error::IntegerOverflow))
+            },
+            _ => return Err(Error::TypeError),
+        };
+        try!(vm.stack.pop());
+        vm.stack.0.rotate_right(shift);
+        Ok(())
+    }));
+    vm.insert_builtin_with_signature("clear", "( ... -- )", Box::new(|vm| {
+        vm.stack.clear();
         Ok(())
     }));
-    vm.insert_builtin("len", Box::new(|vm| {
+    // Pack the whole stack into a list, bottom first.
+    vm.insert_builtin_with_signature("collect", "( ... -- list )", Box::new(|vm| {
+        let items = vm.stack.drain();
+        vm.stack.push(StackItem::List(items))
+    }));
+    // The inverse of `collect`: push each element of a list in order.
+    vm.insert_builtin_with_signature("spread", "( list -- ... )", Box::new(|vm| {
+        match try!(vm.stack.pop()) {
+            StackItem::List(items) => {
+                for item in items {
+                    try!(vm.stack.push(item));
+                }
+                Ok(())
+            },
+            other => {
+                try!(vm.stack.push(other));
+                Err(Error::TypeError)
+            },
+        }
+    }));
+    vm.insert_builtin_with_signature("len", "( ... -- ... n )", Box::new(|vm| {
         let count = try!(FromPrimitive::from_usize(vm.stack.0.len())
                          .ok_or(Error::IntegerOverflow));
-        vm.stack.push(StackItem::Integer(count));
+        try!(vm.stack.push(StackItem::Integer(count)));
         Ok(())
     }));
-    vm.insert_builtin("over", Box::new(|vm| {
-        let b = try!(vm.stack.pop());
-        let a = try!(vm.stack.pop());
-        vm.stack.push(a.clone());
-        vm.stack.push(b);
-        vm.stack.push(a);
+    vm.insert_builtin_with_signature("over", "( a b -- a b a )", Box::new(|vm| {
+        let (a, b) = try!(vm.stack.pop2());
+        try!(vm.stack.push(a.clone()));
+        try!(vm.stack.push(b));
+        try!(vm.stack.push(a));
         Ok(())
     }));
-    vm.insert_builtin("rot", Box::new(|vm| {
-        let c = try!(vm.stack.pop());
-        let b = try!(vm.stack.pop());
-        let a = try!(vm.stack.pop());
-        vm.stack.push(b);
-        vm.stack.push(c);
-        vm.stack.push(a);
+    vm.insert_builtin_with_signature("rot", "( a b c -- b c a )", Box::new(|vm| {
+        let (a, b, c) = try!(vm.stack.pop3());
+        try!(vm.stack.push(b));
+        try!(vm.stack.push(c));
+        try!(vm.stack.push(a));
         Ok(())
     }));
-    vm.insert_builtin("pop", Box::new(|vm| {
+    vm.insert_builtin_with_signature("pop", "( a -- )", Box::new(|vm| {
         let _ = try!(vm.stack.pop());
         Ok(())
     }));
 }
 
-pub fn insert_boolean_ops<I>(vm: &mut Vm<I>) where I: Integer + Clone {
-    vm.insert_builtin("false", Box::new(|vm| {
-        vm.stack.push(StackItem::Boolean(false));
+pub fn insert_boolean_ops<I>(vm: &mut Vm<I>) where I: Integer + Clone + ToString {
+    vm.insert_builtin_with_signature("false", "( -- b )", Box::new(|vm| {
+        try!(vm.stack.push(StackItem::Boolean(false)));
         Ok(())
     }));
-    vm.insert_builtin("true", Box::new(|vm| {
-        vm.stack.push(StackItem::Boolean(true));
+    vm.insert_builtin_with_signature("true", "( -- b )", Box::new(|vm| {
+        try!(vm.stack.push(StackItem::Boolean(true)));
         Ok(())
     }));
-    vm.insert_builtin("eq", Box::new(|vm| {
-        let a = try!(vm.stack.pop());
-        let b = try!(vm.stack.pop());
-        vm.stack.push(StackItem::Boolean(a == b));
+    vm.insert_builtin_with_signature("nil", "( -- nil )", Box::new(|vm| {
+        try!(vm.stack.push(StackItem::Nil));
         Ok(())
     }));
-    vm.insert_builtin("not", Box::new(|vm| {
+    vm.insert_builtin_with_signature("nil?", "( a -- b )", Box::new(|vm| {
         let a = try!(vm.stack.pop());
-        if let StackItem::Boolean(boolean) = a {
-            vm.stack.push(StackItem::Boolean(!boolean));
-        } else {
-            return Err(Error::TypeError)
-        }
+        try!(vm.stack.push(StackItem::Boolean(a == StackItem::Nil)));
         Ok(())
     }));
-    vm.insert_builtin("or", Box::new(|vm| {
-        let b = try!(vm.stack.pop());
-        let a = try!(vm.stack.pop());
+    // Blocks and lists are equal when all their items are, including the
+    // names of calls; as with `f64`, NaN is not equal to itself.
+    vm.insert_builtin_with_signature("eq", "( a a -- b )", Box::new(|vm| {
+        let (a, b) = try!(vm.stack.pop2());
+        try!(vm.stack.push(StackItem::Boolean(items_equal(&a, &b))));
+        Ok(())
+    }));
+    vm.insert_builtin_with_signature("deep-eq", "( a a -- b )", Box::new(|vm| {
+        let (a, b) = try!(vm.stack.pop2());
+        vm.stack.push(StackItem::Boolean(deep_equal(&a, &b)))
+    }));
+    vm.insert_builtin_with_signature("not", "( b -- b )", Box::new(|vm| {
+        let boolean = try!(vm.stack.pop_bool());
+        try!(vm.stack.push(StackItem::Boolean(!boolean)));
+        Ok(())
+    }));
+    vm.insert_builtin_with_signature("or", "( b b -- b )", Box::new(|vm| {
+        let (a, b) = try!(vm.stack.pop2());
         if let (StackItem::Boolean(a), StackItem::Boolean(b)) = (a, b) {
-            vm.stack.push(StackItem::Boolean(a || b));
+            try!(vm.stack.push(StackItem::Boolean(a || b)));
         } else {
             return Err(Error::TypeError);
         }
@@ -217,95 +877,1593 @@ pub fn insert_boolean_ops<I>(vm: &mut Vm<I>) where I: Integer + Clone {
     }));
 }
 
-pub fn insert_string_ops<I>(vm: &mut Vm<I>) where I: Integer + Clone {
-    vm.insert_builtin("cat", Box::new(|vm| {
-        let b = try!(vm.stack.pop());
-        let a = try!(vm.stack.pop());
-        if let (StackItem::String(b), StackItem::String(mut a)) =
-                (b, a) {
-            a.push_str(&b);
-            vm.stack.push(StackItem::String(a));
+// Expand a `format` template, calling `arg` for the text of each `{}` in
+// turn. `{{` and `}}` stand for literal braces; any other brace is kept as is.
+fn expand_template<F>(template: &str, mut arg: F) -> String where F: FnMut() -> String {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some(&'}')) => {
+                chars.next();
+                out.push_str(&arg());
+            },
+            ('{', Some(&'{')) | ('}', Some(&'}')) => {
+                chars.next();
+                out.push(c);
+            },
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn insert_string_ops<I>(vm: &mut Vm<I>)
+        where I: Integer + Clone + FromPrimitive + Display {
+    // Fill each `{}` in the template on top with the items beneath it, taken
+    // in stack order so the deepest fills the first placeholder. Strings are
+    // inserted without their quotes, as by `print`.
+    vm.insert_builtin_with_signature("format", "( ... s -- s )", Box::new(|vm| {
+        let template = try!(vm.stack.pop_string());
+        let mut count = 0;
+        expand_template(&template, || { count += 1; String::new() });
+        let len = vm.stack.0.len();
+        if count > len {
+            try!(vm.stack.push(StackItem::String(template)));
+            return Err(Error::StackUnderflow);
+        }
+        let mut args = vm.stack.split_off(len - count).into_iter();
+        let formatted = expand_template(&template, || match args.next() {
+            Some(StackItem::String(s)) => s,
+            Some(other) => other.to_string(),
+            None => String::new(),
+        });
+        vm.stack.push(StackItem::String(formatted))
+    }));
+    vm.insert_builtin_with_signature("cat", "( s s -- s )", Box::new(|vm| {
+        let (a, b) = try!(vm.stack.pop2());
+        match (a, b) {
+            (StackItem::String(mut a), StackItem::String(b)) => {
+                a.push_str(&b);
+                try!(vm.stack.push(StackItem::String(a)));
+            },
+            (a, b) => {
+                try!(vm.stack.push(a));
+                try!(vm.stack.push(b));
+                return Err(Error::TypeError);
+            },
         }
         Ok(())
     }));
+    vm.insert_builtin_with_signature("str-len", "( s -- i )", Box::new(|vm| {
+        let s = try!(vm.stack.pop_string());
+        let len = try!(FromPrimitive::from_usize(s.chars().count())
+                       .ok_or(Error::IntegerOverflow));
+        try!(vm.stack.push(StackItem::Integer(len)));
+        Ok(())
+    }));
+    // Concatenate a list of strings with a separator between each.
+    vm.insert_builtin_with_signature("join", "( list s -- s )", Box::new(|vm| {
+        let separator = try!(vm.stack.pop_string());
+        let strings: Option<Vec<String>> = match *try!(vm.stack.peek()) {
+            StackItem::List(ref items) => items.iter().map(|item| match *item {
+                StackItem::String(ref s) => Some(s.clone()),
+                _ => None,
+            }).collect(),
+            _ => None,
+        };
+        match strings {
+            Some(strings) => {
+                try!(vm.stack.pop());
+                vm.stack.push(StackItem::String(strings.join(&separator)))
+            },
+            None => {
+                try!(vm.stack.push(StackItem::String(separator)));
+                Err(Error::TypeError)
+            },
+        }
+    }));
 }
 
-pub fn insert_control_flow<I>(vm: &mut Vm<I>) where I: Integer + Clone {
-    vm.insert_builtin("if", Box::new(|vm| {
-        let block = try!(vm.stack.pop());
-        let condition = try!(vm.stack.pop());
-        if let (StackItem::Block(block), StackItem::Boolean(condition)) =
-                (block, condition) {
-            if condition {
-                try!(vm.run_block(&block));
-            }
-        } else {
-            return Err(Error::TypeError);
+pub fn insert_control_flow<I>(vm: &mut Vm<I>)
+        where I: Integer + Clone + ToPrimitive {
+    // On a type error the control-flow builtins push their operands back, so
+    // the stack is left as it was before the call.
+    vm.insert_builtin_with_signature("if", "( b block -- )", Box::new(|vm| {
+        let (condition, block) = try!(vm.stack.pop2());
+        match (condition, block) {
+            (StackItem::Boolean(condition), StackItem::Block(block)) =>
+                if condition {
+                    try!(vm.run_block_tail(block));
+                },
+            (condition, block) => {
+                try!(vm.stack.push(condition));
+                try!(vm.stack.push(block));
+                return Err(Error::TypeError);
+            },
         }
         Ok(())
     }));
-    vm.insert_builtin("ifelse", Box::new(|vm| {
-        let else_block = try!(vm.stack.pop());
-        let if_block = try!(vm.stack.pop());
-        let condition = try!(vm.stack.pop());
-        if let (StackItem::Block(else_block), StackItem::Block(if_block), StackItem::Boolean(condition)) =
-                (else_block, if_block, condition) {
-            if condition {
-                try!(vm.run_block(&if_block));
-            } else {
-                try!(vm.run_block(&else_block));
-            }
-        } else {
-            return Err(Error::TypeError);
+    vm.insert_builtin_with_signature("ifelse", "( b block block -- )", Box::new(|vm| {
+        let (condition, if_block, else_block) = try!(vm.stack.pop3());
+        match (condition, if_block, else_block) {
+            (StackItem::Boolean(condition), StackItem::Block(if_block),
+                    StackItem::Block(else_block)) =>
+                if condition {
+                    try!(vm.run_block_tail(if_block));
+                } else {
+                    try!(vm.run_block_tail(else_block));
+                },
+            (condition, if_block, else_block) => {
+                try!(vm.stack.push(condition));
+                try!(vm.stack.push(if_block));
+                try!(vm.stack.push(else_block));
+                return Err(Error::TypeError);
+            },
         }
         Ok(())
     }));
-    vm.insert_builtin("while", Box::new(|vm| {
-        let action_block = try!(vm.stack.pop());
-        let condition_block = try!(vm.stack.pop());
-        if let (StackItem::Block(action_block), StackItem::Block(condition_block)) =
-                (action_block, condition_block) {
-            loop {
-                try!(vm.run_block(&condition_block));
-                let condition = try!(vm.stack.pop());
-                if let StackItem::Boolean(condition) = condition {
-                    if condition {
-                        try!(vm.run_block(&action_block));
-                    } else {
-                        break;
+    // If the condition block produces a non-boolean, that value is left on
+    // top of the stack when the error is returned.
+    vm.insert_builtin_with_signature("while", "( block block -- )", Box::new(|vm| {
+        let (condition_block, action_block) = try!(vm.stack.pop2());
+        match (condition_block, action_block) {
+            (StackItem::Block(condition_block), StackItem::Block(action_block)) =>
+                loop {
+                    try!(vm.run_block(&condition_block));
+                    match try!(vm.stack.pop()) {
+                        StackItem::Boolean(true) =>
+                            try!(vm.run_block(&action_block)),
+                        StackItem::Boolean(false) => break,
+                        condition => {
+                            try!(vm.stack.push(condition));
+                            return Err(Error::TypeError);
+                        },
                     }
+                },
+            (condition_block, action_block) => {
+                try!(vm.stack.push(condition_block));
+                try!(vm.stack.push(action_block));
+                return Err(Error::TypeError);
+            },
+        }
+        Ok(())
+    }));
+    vm.insert_builtin_with_signature("times", "( i block -- )", Box::new(|vm| {
+        let (times, block) = try!(vm.stack.pop2());
+        match (times, block) {
+            (StackItem::Integer(mut times), StackItem::Block(block)) =>
+                while times > zero() {
+                    try!(vm.run_block(&block));
+                    times = times - one::<I>();
+                },
+            (times, block) => {
+                try!(vm.stack.push(times));
+                try!(vm.stack.push(block));
+                return Err(Error::TypeError);
+            },
+        }
+        Ok(())
+    }));
+    // Run the block at an index into a list of blocks.
+    vm.insert_builtin_with_signature("jump", "( i list -- )", Box::new(|vm| {
+        let (index, blocks) = try!(vm.stack.pop2());
+        let selected = match (&index, &blocks) {
+            (StackItem::Integer(i), StackItem::List(blocks)) =>
+                match i.to_usize().and_then(|i| blocks.get(i)) {
+                    Some(StackItem::Block(block)) => Ok(block.clone()),
+                    Some(_) => Err(Error::TypeError),
+                    None => Err(Error::OutOfBounds),
+                },
+            _ => Err(Error::TypeError),
+        };
+        match selected {
+            Ok(block) => vm.run_block_tail(block),
+            Err(err) => {
+                try!(vm.stack.push(index));
+                try!(vm.stack.push(blocks));
+                Err(err)
+            },
+        }
+    }));
+    vm.insert_builtin_with_signature("call", "( block -- )", Box::new(|vm| {
+        let block = try!(vm.stack.pop_block());
+        vm.run_block_tail(block)
+    }));
+    // Run a block against a copy of the top item, then throw away whatever
+    // the block left so the original top passes through unchanged.
+    // If the block fails, the stack is put back as it was before the call.
+    vm.insert_builtin_with_signature("tee", "( a block -- a )", Box::new(|vm| {
+        if vm.stack.0.len() < 2 {
+            return Err(Error::StackUnderflow);
+        }
+        let block = try!(vm.stack.pop_block());
+        let top = try!(vm.stack.pop());
+        let depth = vm.stack.0.len();
+        try!(vm.stack.push(top.clone()));
+        let result = vm.run_block(&block);
+        vm.stack.truncate(depth);
+        try!(vm.stack.push(top));
+        if result.is_err() {
+            try!(vm.stack.push(StackItem::Block(block)));
+        }
+        result
+    }));
+    // Run a block, pushing `true` if it succeeded. On failure, anything the
+    // block pushed is discarded and the error and `false` are pushed instead,
+    // and the error is kept for `last-error`. Only the stack is
+    // restored: methods defined by the block via `fn` stay defined.
+    vm.insert_builtin_with_signature("try", "( block -- ... b )", Box::new(|vm| {
+        let block = try!(vm.stack.pop_block());
+        let depth = vm.stack.0.len();
+        match vm.run_block(&block) {
+            Ok(()) => try!(vm.stack.push(StackItem::Boolean(true))),
+            Err(Error::Quit) => return Err(Error::Quit),
+            Err(e) => {
+                vm.stack.truncate(depth);
+                try!(vm.stack.push(StackItem::Error(e.clone())));
+                try!(vm.stack.push(StackItem::Boolean(false)));
+                vm.last_error = Some(e);
+            },
+        }
+        Ok(())
+    }));
+    vm.insert_builtin_with_signature("last-error", "( -- e )", Box::new(|vm| {
+        let item = match vm.last_error {
+            Some(ref e) => StackItem::Error(e.clone()),
+            None => StackItem::Nil,
+        };
+        try!(vm.stack.push(item));
+        Ok(())
+    }));
+    vm.insert_builtin_with_signature("error-kind", "( e -- sym )", Box::new(|vm| {
+        match try!(vm.stack.pop()) {
+            StackItem::Error(e) =>
+                try!(vm.stack.push(StackItem::Symbol(e.kind().to_string()))),
+            other => {
+                try!(vm.stack.push(other));
+                return Err(Error::TypeError);
+            },
+        }
+        Ok(())
+    }));
+    vm.insert_builtin_with_signature("error?", "( a -- b )", Box::new(|vm| {
+        let is_error = matches!(try!(vm.stack.pop()), StackItem::Error(_));
+        vm.stack.push(StackItem::Boolean(is_error))
+    }));
+    // The human-readable description of an error, as printed by the REPL.
+    vm.insert_builtin_with_signature("error-message", "( e -- s )", Box::new(|vm| {
+        match try!(vm.stack.pop()) {
+            StackItem::Error(e) => vm.stack.push(StackItem::String(e.to_string())),
+            other => {
+                try!(vm.stack.push(other));
+                Err(Error::TypeError)
+            },
+        }
+    }));
+    // Stop the program, asking the host to exit with status 0, or with the
+    // given status for `exit`.
+    vm.insert_builtin_with_signature("quit", "( -- )", Box::new(|vm| {
+        vm.quit_requested = Some(0);
+        Err(Error::Quit)
+    }));
+    vm.insert_builtin_with_signature("exit", "( i -- )", Box::new(|vm| {
+        let status = match *try!(vm.stack.peek()) {
+            StackItem::Integer(ref status) => try!(status.to_i32().ok_or(Error::NumericConversion)),
+            _ => return Err(Error::TypeError),
+        };
+        try!(vm.stack.pop());
+        vm.quit_requested = Some(status);
+        Err(Error::Quit)
+    }));
+    // Raise the error last caught by `try` again; does nothing if `try` has
+    // not caught anything.
+    vm.insert_builtin_with_signature("rethrow", "( -- )", Box::new(|vm| {
+        match vm.last_error {
+            Some(ref e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }));
+}
+
+pub fn insert_block_ops<I>(vm: &mut Vm<I>) where I: Integer + Clone + FromPrimitive {
+    vm.insert_builtin_with_signature("compose", "( block block -- block )", Box::new(|vm| {
+        let (a, b) = try!(vm.stack.pop2());
+        match (a, b) {
+            (StackItem::Block(mut a), StackItem::Block(b)) => {
+                Rc::make_mut(&mut a).0.extend(b.0.iter().cloned());
+                try!(vm.stack.push(StackItem::Block(a)));
+            },
+            (a, b) => {
+                try!(vm.stack.push(a));
+                try!(vm.stack.push(b));
+                return Err(Error::TypeError);
+            },
+        }
+        Ok(())
+    }));
+    // The number of items in a block, not counting those in nested blocks.
+    vm.insert_builtin_with_signature("block-len", "( block -- i )", Box::new(|vm| {
+        let block = try!(vm.stack.pop_block());
+        let len = try!(FromPrimitive::from_usize(block.0.len())
+                       .ok_or(Error::IntegerOverflow));
+        vm.stack.push(StackItem::Integer(len))
+    }));
+    vm.insert_builtin_with_signature("block-reverse", "( block -- block )", Box::new(|vm| {
+        let mut block = try!(vm.stack.pop_block());
+        Rc::make_mut(&mut block).0.reverse();
+        vm.stack.push(StackItem::Block(block))
+    }));
+    // Quote a value as a block which pushes it when called.
+    vm.insert_builtin_with_signature("wrap", "( a -- block )", Box::new(|vm| {
+        let item = try!(vm.stack.pop());
+        let block = Block(vec![BlockItem::Literal(item)]);
+        vm.stack.push(StackItem::Block(Rc::new(block)))
+    }));
+    // The inverse of `wrap`, for blocks holding a single literal.
+    vm.insert_builtin_with_signature("unwrap", "( block -- a )", Box::new(|vm| {
+        let block = try!(vm.stack.pop_block());
+        let item = match block.0.as_slice() {
+            [BlockItem::Literal(item)] => item.clone(),
+            _ => {
+                try!(vm.stack.push(StackItem::Block(block)));
+                return Err(Error::TypeError);
+            },
+        };
+        vm.stack.push(item)
+    }));
+}
+
+// Order two keys computed by `min-by` or `max-by`, which must be numbers or
+// strings of the same type.
+fn compare_keys<I>(a: &StackItem<I>, b: &StackItem<I>) -> Result<cmp::Ordering, Error>
+        where I: Integer {
+    match (a, b) {
+        (StackItem::Integer(a), StackItem::Integer(b)) => Ok(a.cmp(b)),
+        (StackItem::Float(a), StackItem::Float(b)) =>
+            a.partial_cmp(b).ok_or(Error::TypeError),
+        (StackItem::Rational(a), StackItem::Rational(b)) => Ok(a.cmp(b)),
+        (StackItem::String(a), StackItem::String(b)) => Ok(a.cmp(b)),
+        _ => Err(Error::TypeError),
+    }
+}
+
+// Define a builtin pushing the first element of a list whose key, computed by
+// a block, is ordered `wanted` relative to every other key. On error the list
+// and block are put back.
+fn insert_extreme_by<I>(vm: &mut Vm<I>, name: &str, wanted: cmp::Ordering)
+        where I: Integer + Clone {
+    vm.insert_builtin_with_signature(name, "( list block -- a )", Box::new(move |vm| {
+        let (list, key_block) = try!(vm.stack.pop2());
+        let depth = vm.stack.0.len();
+        let extreme = match (&list, &key_block) {
+            (StackItem::List(items), StackItem::Block(key_block)) =>
+                extreme_by(vm, items, key_block, wanted),
+            _ => Err(Error::TypeError),
+        };
+        match extreme {
+            Ok(item) => vm.stack.push(item),
+            Err(e) => {
+                vm.stack.truncate(depth);
+                try!(vm.stack.push(list));
+                try!(vm.stack.push(key_block));
+                Err(e)
+            },
+        }
+    }));
+}
+
+fn extreme_by<I>(vm: &mut Vm<I>, items: &[StackItem<I>], key_block: &Block<I>,
+                 wanted: cmp::Ordering) -> Result<StackItem<I>, Error>
+        where I: Integer + Clone {
+    let mut best: Option<(&StackItem<I>, StackItem<I>)> = None;
+    for item in items {
+        try!(vm.stack.push(item.clone()));
+        try!(vm.run_block(key_block));
+        let key = try!(vm.stack.pop());
+        best = match best {
+            Some((best_item, best_key)) =>
+                if try!(compare_keys(&key, &best_key)) == wanted {
+                    Some((item, key))
                 } else {
-                    return Err(Error::TypeError);
-                }
+                    Some((best_item, best_key))
+                },
+            None => Some((item, key)),
+        };
+    }
+    best.map(|(item, _)| item.clone()).ok_or(Error::OutOfBounds)
+}
+
+// Pop a value and the list beneath it, leaving both in place unless the
+// second is a list.
+fn pop_list_and_value<I>(vm: &mut Vm<I>)
+        -> Result<(Vec<StackItem<I>>, StackItem<I>), Error> where I: Clone {
+    match try!(vm.stack.pop2()) {
+        (StackItem::List(items), value) => Ok((items, value)),
+        (list, value) => {
+            try!(vm.stack.push(list));
+            try!(vm.stack.push(value));
+            Err(Error::TypeError)
+        },
+    }
+}
+
+pub fn insert_list_ops<I>(vm: &mut Vm<I>)
+        where I: Integer + Clone + ToPrimitive + FromPrimitive + ToString {
+    // Both compare elements like `eq`.
+    vm.insert_builtin_with_signature("list-contains", "( list a -- b )", Box::new(|vm| {
+        let (items, value) = try!(pop_list_and_value(vm));
+        let found = items.iter().any(|item| items_equal(item, &value));
+        vm.stack.push(StackItem::Boolean(found))
+    }));
+    // The index of the first matching element, or `nil` if there is none.
+    vm.insert_builtin_with_signature("list-index-of", "( list a -- i )", Box::new(|vm| {
+        let (items, value) = try!(pop_list_and_value(vm));
+        let index = match items.iter().position(|item| items_equal(item, &value)) {
+            Some(index) => StackItem::Integer(try!(FromPrimitive::from_usize(index)
+                                                   .ok_or(Error::IntegerOverflow))),
+            None => StackItem::Nil,
+        };
+        vm.stack.push(index)
+    }));
+    // Run a block on the accumulator and each element in turn, keeping the top
+    // item it leaves as the new accumulator. Anything else the block leaves
+    // is discarded.
+    vm.insert_builtin_with_signature("fold", "( list a block -- a )", Box::new(|vm| {
+        let (list, init, block) = try!(vm.stack.pop3());
+        let (items, block) = match (list, block) {
+            (StackItem::List(items), StackItem::Block(block)) => (items, block),
+            (list, block) => {
+                try!(vm.stack.push(list));
+                try!(vm.stack.push(init));
+                try!(vm.stack.push(block));
+                return Err(Error::TypeError);
+            },
+        };
+        let depth = vm.stack.0.len();
+        let mut acc = init;
+        for item in items {
+            try!(vm.stack.push(acc));
+            try!(vm.stack.push(item));
+            try!(vm.run_block(&block));
+            if vm.stack.0.len() <= depth {
+                return Err(Error::StackUnderflow);
             }
-        } else {
-            return Err(Error::TypeError);
+            acc = try!(vm.stack.pop());
+            vm.stack.truncate(depth);
+        }
+        vm.stack.push(acc)
+    }));
+    // Keep the elements for which a block leaves `true` on top. Anything else
+    // the block leaves is discarded.
+    vm.insert_builtin_with_signature("filter", "( list block -- list )", Box::new(|vm| {
+        let (list, block) = try!(vm.stack.pop2());
+        let (items, block) = match (list, block) {
+            (StackItem::List(items), StackItem::Block(block)) => (items, block),
+            (list, block) => {
+                try!(vm.stack.push(list));
+                try!(vm.stack.push(block));
+                return Err(Error::TypeError);
+            },
+        };
+        let depth = vm.stack.0.len();
+        let mut kept = Vec::new();
+        for item in items {
+            try!(vm.stack.push(item.clone()));
+            try!(vm.run_block(&block));
+            if vm.stack.0.len() <= depth {
+                return Err(Error::StackUnderflow);
+            }
+            if try!(vm.stack.pop_bool()) {
+                kept.push(item);
+            }
+            vm.stack.truncate(depth);
         }
+        vm.stack.push(StackItem::List(kept))
+    }));
+    // Sort a list in the order given by `StackItem::total_cmp`.
+    vm.insert_builtin_with_signature("sort", "( list -- list )", Box::new(|vm| {
+        match try!(vm.stack.pop()) {
+            StackItem::List(mut items) => {
+                items.sort_by(|a, b| a.total_cmp(b));
+                vm.stack.push(StackItem::List(items))
+            },
+            other => {
+                try!(vm.stack.push(other));
+                Err(Error::TypeError)
+            },
+        }
+    }));
+    insert_extreme_by(vm, "min-by", cmp::Ordering::Less);
+    insert_extreme_by(vm, "max-by", cmp::Ordering::Greater);
+}
+
+// Split a dotted version such as "1.2.3" into its numeric components.
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// The interpreter version, for feature detection in scripts.
+pub fn insert_version<I>(vm: &mut Vm<I>) where I: Integer + Clone {
+    vm.insert_builtin_with_signature("version", "( -- s )", Box::new(|vm| {
+        try!(vm.stack.push(StackItem::String(env!("CARGO_PKG_VERSION").to_string())));
+        Ok(())
+    }));
+    // Missing trailing components count as zero, so "1" is the same as "1.0.0".
+    vm.insert_builtin_with_signature("version-at-least?", "( s -- b )", Box::new(|vm| {
+        let mut wanted = match *try!(vm.stack.peek()) {
+            StackItem::String(ref s) => try!(parse_version(s).ok_or(Error::NumericConversion)),
+            _ => return Err(Error::TypeError),
+        };
+        try!(vm.stack.pop());
+        let mut current = parse_version(env!("CARGO_PKG_VERSION"))
+            .expect("crate version should be numeric");
+        let len = cmp::max(wanted.len(), current.len());
+        wanted.resize(len, 0);
+        current.resize(len, 0);
+        try!(vm.stack.push(StackItem::Boolean(current >= wanted)));
         Ok(())
     }));
-    vm.insert_builtin("times", Box::new(|vm| {
-        let block = try!(vm.stack.pop());
-        let times = try!(vm.stack.pop());
-        if let (StackItem::Block(block), StackItem::Integer(mut times)) =
-                (block, times) {
-            while times > zero() {
-                try!(vm.run_block(&block));
-                times = times - one::<I>();
+}
+
+/// Builtins writing to the VM's output.
+// Builtins here take their arguments as usual but write nothing in a dry run.
+pub fn insert_io<I>(vm: &mut Vm<I>) where I: Integer + Clone + Display {
+    // Strings are printed without their quotes.
+    vm.insert_builtin_with_signature("print", "( a -- )", Box::new(|vm| {
+        let item = try!(vm.stack.pop());
+        if vm.dry_run {
+            return Ok(());
+        }
+        match item {
+            StackItem::String(s) => try!(writeln!(vm.output, "{}", s)),
+            other => try!(writeln!(vm.output, "{}", other)),
+        }
+        Ok(())
+    }));
+    vm.insert_builtin_with_signature("show-stack", "( -- )", Box::new(|vm| {
+        if !vm.dry_run {
+            try!(writeln!(vm.output, "{}", vm.stack));
+        }
+        Ok(())
+    }));
+    // Read one line without its line ending, or `nil` at the end of input.
+    // A dry run never reads, and always sees the end of input.
+    vm.insert_builtin_with_signature("read-line", "( -- s )", Box::new(|vm| {
+        let mut line = String::new();
+        if !vm.dry_run && try!(vm.reader.read_line(&mut line)) > 0 {
+            if line.ends_with('\n') {
+                line.pop();
+                if line.ends_with('\r') {
+                    line.pop();
+                }
             }
+            vm.stack.push(StackItem::String(line))
         } else {
-            return Err(Error::TypeError);
+            vm.stack.push(StackItem::Nil)
+        }
+    }));
+}
+
+/// File access, for scripts trusted with the filesystem. Not included in
+/// `insert_all`.
+pub fn insert_fs<I>(vm: &mut Vm<I>) where I: Integer + Clone {
+    // A dry run never reads, and sees every file as empty.
+    vm.insert_builtin_with_signature("read-file", "( s -- s )", Box::new(|vm| {
+        let contents = match *try!(vm.stack.peek()) {
+            StackItem::String(_) if vm.dry_run => String::new(),
+            StackItem::String(ref filename) => try!(fs::read_to_string(filename)),
+            _ => return Err(Error::TypeError),
+        };
+        try!(vm.stack.pop());
+        vm.stack.push(StackItem::String(contents))
+    }));
+    // Replaces the file if it exists; does nothing in a dry run.
+    vm.insert_builtin_with_signature("write-file", "( s s -- )", Box::new(|vm| {
+        let filename = try!(vm.stack.pop_string());
+        let contents = match vm.stack.pop_string() {
+            Ok(contents) => contents,
+            Err(e) => {
+                try!(vm.stack.push(StackItem::String(filename)));
+                return Err(e);
+            },
+        };
+        if !vm.dry_run {
+            if let Err(e) = fs::write(&filename, &contents) {
+                try!(vm.stack.push(StackItem::String(contents)));
+                try!(vm.stack.push(StackItem::String(filename)));
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }));
+}
+
+// Advance a xorshift generator, whose state must never be zero.
+fn xorshift(state: &Cell<u64>) -> u64 {
+    let mut x = state.get();
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    state.set(x);
+    x
+}
+
+// Any seed is allowed, but zero would leave xorshift stuck at zero.
+fn seed_state(seed: u64) -> u64 {
+    if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed }
+}
+
+/// A pseudo-random number generator, seeded from the clock until `seed` is
+/// called. Not included in `insert_all`.
+pub fn insert_random<I>(vm: &mut Vm<I>)
+        where I: Integer + Clone + ToPrimitive + FromPrimitive {
+    let clock = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() ^ u64::from(d.subsec_nanos()))
+        .unwrap_or(0);
+    let state = Rc::new(Cell::new(seed_state(clock)));
+    let generator = state.clone();
+    vm.insert_builtin_with_signature("random", "( i -- i )", Box::new(move |vm| {
+        let bound = match *try!(vm.stack.peek()) {
+            StackItem::Integer(ref bound) => match bound.to_u64() {
+                Some(0) => return Err(Error::OutOfBounds),
+                Some(bound) => bound,
+                None if *bound < zero() => return Err(Error::OutOfBounds),
+                None => return Err(Error::NumericConversion),
+            },
+            _ => return Err(Error::TypeError),
+        };
+        let n = xorshift(&generator) % bound;
+        let n = try!(I::from_u64(n).ok_or(Error::NumericConversion));
+        try!(vm.stack.pop());
+        vm.stack.push(StackItem::Integer(n))
+    }));
+    // Negative seeds are as good as any other.
+    vm.insert_builtin_with_signature("seed", "( i -- )", Box::new(move |vm| {
+        let seed = match *try!(vm.stack.peek()) {
+            StackItem::Integer(ref seed) => try!(seed.to_i64().ok_or(Error::NumericConversion)),
+            _ => return Err(Error::TypeError),
+        };
+        try!(vm.stack.pop());
+        state.set(seed_state(seed as u64));
+        Ok(())
+    }));
+}
+
+/// A wall clock. Not included in `insert_all`.
+pub fn insert_time<I>(vm: &mut Vm<I>)
+        where I: Integer + Clone + ToPrimitive + FromPrimitive {
+    // Milliseconds since the Unix epoch.
+    vm.insert_builtin_with_signature("now", "( -- i )", Box::new(|vm| {
+        let since_epoch = try!(SystemTime::now().duration_since(UNIX_EPOCH)
+            .map_err(|e| Error::Io(e.to_string())));
+        let millis = since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis());
+        let millis = try!(I::from_u64(millis).ok_or(Error::NumericConversion));
+        vm.stack.push(StackItem::Integer(millis))
+    }));
+    // Pause for a number of milliseconds; a dry run does not wait.
+    vm.insert_builtin_with_signature("sleep", "( i -- )", Box::new(|vm| {
+        let millis = match *try!(vm.stack.peek()) {
+            StackItem::Integer(ref millis) if *millis < zero() => return Err(Error::OutOfBounds),
+            StackItem::Integer(ref millis) => try!(millis.to_u64().ok_or(Error::IntegerOverflow)),
+            _ => return Err(Error::TypeError),
+        };
+        try!(vm.stack.pop());
+        if !vm.dry_run {
+            thread::sleep(Duration::from_millis(millis));
         }
         Ok(())
     }));
 }
 
+/// Parsing and running source code built at runtime. Not included in
+/// `insert_all`.
+pub fn insert_eval<I>(vm: &mut Vm<I>) where I: Integer + Clone + FromStr {
+    // The source is only popped once it parses.
+    vm.insert_builtin_with_signature("eval", "( s -- ... )", Box::new(|vm| {
+        let block = try!(parse_top(vm));
+        try!(vm.stack.pop());
+        vm.run_block_tail(Rc::new(block))
+    }));
+    // Like `eval`, but push the block instead of running it.
+    vm.insert_builtin_with_signature("compile", "( s -- block )", Box::new(|vm| {
+        let block = try!(parse_top(vm));
+        try!(vm.stack.pop());
+        vm.stack.push(StackItem::Block(Rc::new(block)))
+    }));
+}
+
+// Parse the string on top of the stack, leaving it there.
+fn parse_top<I>(vm: &Vm<I>) -> Result<Block<I>, Error> where I: Integer + Clone + FromStr {
+    match *try!(vm.stack.peek()) {
+        StackItem::String(ref src) => parse(src).map_err(|e| Error::ParseError(e.to_string())),
+        _ => Err(Error::TypeError),
+    }
+}
+
+pub fn insert_assertions<I>(vm: &mut Vm<I>) where I: Integer + Clone + Display {
+    vm.insert_builtin_with_signature("assert", "( b -- )", Box::new(|vm| {
+        if try!(vm.stack.pop_bool()) {
+            Ok(())
+        } else {
+            Err(Error::AssertionFailed("false".to_string()))
+        }
+    }));
+    // Compares like `eq`.
+    vm.insert_builtin_with_signature("assert-eq", "( a a -- )", Box::new(|vm| {
+        let (a, b) = try!(vm.stack.pop2());
+        if items_equal(&a, &b) {
+            Ok(())
+        } else {
+            Err(Error::AssertionFailed(format!("{} != {}", a, b)))
+        }
+    }));
+}
+
 pub fn insert_all<I>(vm: &mut Vm<I>)
-        where I: Integer + Clone + ToPrimitive + FromPrimitive + ToString {
+        where I: Integer + Clone + ToPrimitive + FromPrimitive + ToString + Debug
+                 + Display + CheckedAdd + CheckedSub + CheckedMul + CheckedDiv {
     insert_arithmetic(vm);
+    insert_float_math(vm);
+    insert_transcendental(vm);
+    insert_comparison(vm);
     insert_conversions(vm);
     insert_fn(vm);
     insert_stack_ops(vm);
     insert_boolean_ops(vm);
     insert_string_ops(vm);
     insert_control_flow(vm);
+    insert_block_ops(vm);
+    insert_list_ops(vm);
+    insert_io(vm);
+    insert_assertions(vm);
+    insert_version(vm);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::{env, fs, process};
+    use std::time::{Duration, Instant};
+    use item::StackItem;
+    use parse::parse;
+    use vm::{self, Vm};
+    use super::{insert_all, insert_eval, insert_fs, insert_random, insert_time};
+
+    fn new_vm() -> Vm<i64> {
+        let mut vm = Vm::new();
+        insert_all(&mut vm);
+        vm
+    }
+
+    fn eval(src: &str) -> vm::Result<Vec<StackItem<i64>>> {
+        let mut vm = new_vm();
+        try!(vm.run_block(&parse(src).unwrap()));
+        Ok(vm.stack.0)
+    }
+
+    // Run `src`, which must fail, and return what it left on the stack.
+    fn stack_after_error(src: &str) -> Vec<StackItem<i64>> {
+        let mut vm = new_vm();
+        assert!(vm.run_block(&parse(src).unwrap()).is_err());
+        vm.stack.0
+    }
+
+    fn string(s: &str) -> StackItem<i64> {
+        StackItem::String(s.to_string())
+    }
+
+    #[test]
+    fn test_type_of() {
+        assert_eq!(eval("1 type-of"), Ok(vec![string("integer")]));
+        assert_eq!(eval("1.0 type-of"), Ok(vec![string("float")]));
+        assert_eq!(eval("\"s\" type-of"), Ok(vec![string("string")]));
+        assert_eq!(eval("true type-of"), Ok(vec![string("boolean")]));
+        assert_eq!(eval(":s type-of"), Ok(vec![string("symbol")]));
+        assert_eq!(eval("{ } type-of"), Ok(vec![string("block")]));
+        assert_eq!(eval("nil type-of"), Ok(vec![string("nil")]));
+        assert_eq!(eval("[] type-of"), Ok(vec![string("list")]));
+        assert_eq!(eval("'c' type-of"), Ok(vec![string("char")]));
+    }
+
+    #[test]
+    fn test_chars() {
+        assert_eq!(eval("'A' char->int"), Ok(vec![StackItem::Integer(65)]));
+        assert_eq!(eval("955 int->char"), Ok(vec![StackItem::Char('λ')]));
+        assert_eq!(eval("55296 int->char"), Err(vm::Error::NumericConversion));
+        assert_eq!(eval("'a' to-string"), Ok(vec![string("a")]));
+        assert_eq!(eval("'a' 'a' eq"), Ok(vec![StackItem::Boolean(true)]));
+    }
+
+    #[test]
+    fn test_try() {
+        assert_eq!(eval("{ 1 2 + } try"),
+            Ok(vec![StackItem::Integer(3), StackItem::Boolean(true)]));
+        assert_eq!(eval("5 { 1 2 0 / } try"),
+            Ok(vec![StackItem::Integer(5), StackItem::Error(vm::Error::DivideByZero),
+                    StackItem::Boolean(false)]));
+        assert_eq!(eval("1 try"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_execute_symbol() {
+        assert_eq!(eval("2 3 :+ execute-symbol"), Ok(vec![StackItem::Integer(5)]));
+        assert_eq!(eval(":nope execute-symbol"),
+            Err(vm::Error::UnknownMethod("nope".to_string())));
+    }
+
+    #[test]
+    fn test_quoted_call() {
+        assert_eq!(eval(":\"add two\" { 2 + } fn 1 `add two`"),
+            Ok(vec![StackItem::Integer(3)]));
+    }
+
+    #[test]
+    fn test_words() {
+        let stack = eval("words").unwrap();
+        let words: Vec<_> = match stack[0] {
+            StackItem::List(ref words) => words.iter().map(|w| match *w {
+                StackItem::String(ref s) => s.clone(),
+                _ => panic!("words should only contain strings"),
+            }).collect(),
+            _ => panic!("words should push a list"),
+        };
+        assert!(words.contains(&"+".to_string()));
+        assert!(words.contains(&"swap".to_string()));
+        let mut sorted = words.clone();
+        sorted.sort();
+        assert_eq!(words, sorted);
+    }
+
+    #[test]
+    fn test_redefine_builtin() {
+        assert_eq!(eval(":+ { pop } fn"),
+            Err(vm::Error::RedefineBuiltin("+".to_string())));
+        assert_eq!(eval(":+ { pop } fn! 2 3 +"), Ok(vec![StackItem::Integer(2)]));
+        assert_eq!(eval(":f { 1 } fn :f { 2 } fn f"), Ok(vec![StackItem::Integer(2)]));
+    }
+
+    #[test]
+    fn test_clamp() {
+        assert_eq!(eval("15 0 10 clamp"), Ok(vec![StackItem::Integer(10)]));
+        assert_eq!(eval("0 3 - 0 10 clamp"), Ok(vec![StackItem::Integer(0)]));
+        assert_eq!(eval("5 0 10 clamp"), Ok(vec![StackItem::Integer(5)]));
+        assert_eq!(eval("1.5 0.0 1.0 clamp"), Ok(vec![StackItem::Float(1.0)]));
+        assert_eq!(eval("5 10 0 clamp"), Err(vm::Error::OutOfBounds));
+        assert_eq!(stack_after_error("5 10 0 clamp"),
+            vec![StackItem::Integer(5), StackItem::Integer(10), StackItem::Integer(0)]);
+        assert_eq!(eval("5 0.0 10 clamp"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_sign_predicates() {
+        let t = StackItem::Boolean(true);
+        let f = StackItem::Boolean(false);
+        assert_eq!(eval("5 zero? 0 zero? 0 5 - zero?"),
+            Ok(vec![f.clone(), t.clone(), f.clone()]));
+        assert_eq!(eval("5 positive? 0 positive? 0 5 - positive?"),
+            Ok(vec![t.clone(), f.clone(), f.clone()]));
+        assert_eq!(eval("5 negative? 0 negative? 0 5 - negative?"),
+            Ok(vec![f.clone(), f.clone(), t.clone()]));
+        assert_eq!(eval("0.5 positive? 0.0 zero? 0.0 0.5 - negative? nan zero?"),
+            Ok(vec![t.clone(), t.clone(), t.clone(), f.clone()]));
+        assert_eq!(eval("\"0\" zero?"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_assert() {
+        assert_eq!(eval("1 1 eq assert"), Ok(vec![]));
+        assert_eq!(eval("1 2 eq assert"),
+            Err(vm::Error::AssertionFailed("false".to_string())));
+        assert_eq!(eval("1 assert"), Err(vm::Error::TypeError));
+        assert_eq!(eval("\"a\" \"a\" assert-eq"), Ok(vec![]));
+        let err = eval("1 \"1\" assert-eq").unwrap_err();
+        assert_eq!(err, vm::Error::AssertionFailed("1 != \"1\"".to_string()));
+        assert_eq!(err.to_string(), "Assertion failed: 1 != \"1\"");
+    }
+
+    #[test]
+    fn test_fs() {
+        let path = env::temp_dir().join(format!("rustpn-test-fs-{}", process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut vm = new_vm();
+        insert_fs(&mut vm);
+        vm.inject(&[string("line one\nline two"), string(&path)]).unwrap();
+        assert_eq!(vm.run_block(&parse("write-file").unwrap()), Ok(()));
+        vm.inject(&[string(&path)]).unwrap();
+        let result = vm.run_block(&parse("read-file").unwrap());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.stack.0, vec![string("line one\nline two")]);
+        vm.stack.clear();
+        vm.inject(&[string(&path)]).unwrap();
+        match vm.run_block(&parse("read-file").unwrap()) {
+            Err(vm::Error::Io(_)) => {},
+            other => panic!("expected an I/O error, got {:?}", other),
+        }
+        assert_eq!(vm.stack.0, vec![string(&path)]);
+        let missing = format!("{}/missing", path);
+        vm.stack.clear();
+        vm.inject(&[string("x"), string(&missing)]).unwrap();
+        match vm.run_block(&parse("write-file").unwrap()) {
+            Err(vm::Error::Io(_)) => {},
+            other => panic!("expected an I/O error, got {:?}", other),
+        }
+        assert_eq!(vm.stack.0, vec![string("x"), string(&missing)]);
+    }
+
+    #[test]
+    fn test_random() {
+        let mut vm = new_vm();
+        insert_random(&mut vm);
+        let program = parse("42 seed [ 10 { 100 random } times ]").unwrap();
+        vm.run_block(&program).unwrap();
+        vm.run_block(&program).unwrap();
+        let (first, second) = vm.stack.pop2().unwrap();
+        assert_eq!(first, second);
+        match first {
+            StackItem::List(ref items) => {
+                assert_eq!(items.len(), 10);
+                for item in items {
+                    match *item {
+                        StackItem::Integer(n) => assert!((0..100).contains(&n)),
+                        _ => panic!("random should push integers"),
+                    }
+                }
+            },
+            _ => panic!("expected a list"),
+        }
+        assert_eq!(vm.run_block(&parse("0 random").unwrap()), Err(vm::Error::OutOfBounds));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(0)]);
+    }
+
+    #[test]
+    fn test_now() {
+        let mut vm = new_vm();
+        insert_time(&mut vm);
+        vm.run_block(&parse("now now").unwrap()).unwrap();
+        match (&vm.stack.0[0], &vm.stack.0[1]) {
+            (StackItem::Integer(first), StackItem::Integer(second)) =>
+                assert!(first > &0 && first <= second),
+            _ => panic!("now should push integers"),
+        }
+    }
+
+    #[test]
+    fn test_sleep() {
+        let mut vm = new_vm();
+        insert_time(&mut vm);
+        let start = Instant::now();
+        assert_eq!(vm.run_block(&parse("5 sleep").unwrap()), Ok(()));
+        assert!(start.elapsed() >= Duration::from_millis(5));
+        assert!(vm.stack.0.is_empty());
+        assert_eq!(vm.run_block(&parse("0 5 - sleep").unwrap()),
+            Err(vm::Error::OutOfBounds));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(-5)]);
+    }
+
+    #[test]
+    fn test_block_len_and_reverse() {
+        assert_eq!(eval("{ 1 2 3 } block-len"), Ok(vec![StackItem::Integer(3)]));
+        assert_eq!(eval("{ 1 { 2 3 } } block-len"), Ok(vec![StackItem::Integer(2)]));
+        assert_eq!(eval("{ 1 2 3 } block-reverse call"),
+            Ok(vec![StackItem::Integer(3), StackItem::Integer(2), StackItem::Integer(1)]));
+        assert_eq!(eval("{ 1 2 3 } clone block-reverse pop call"),
+            Ok(vec![StackItem::Integer(1), StackItem::Integer(2), StackItem::Integer(3)]));
+        assert_eq!(eval("1 block-len"), Err(vm::Error::TypeError));
+        assert_eq!(eval("[1] block-reverse"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_wrap_unwrap() {
+        assert_eq!(eval("5 wrap call"), Ok(vec![StackItem::Integer(5)]));
+        assert_eq!(eval("5 wrap block-len"), Ok(vec![StackItem::Integer(1)]));
+        assert_eq!(eval("{ 1 } wrap unwrap call"), Ok(vec![StackItem::Integer(1)]));
+        assert_eq!(eval("\"s\" wrap unwrap"), Ok(vec![string("s")]));
+        assert_eq!(eval("{ 1 2 } unwrap"), Err(vm::Error::TypeError));
+        assert_eq!(eval("{ swap } unwrap"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_rational() {
+        let half = "1 2 make-rational";
+        assert_eq!(eval("1 3 make-rational 1 6 make-rational + to-string"),
+            Ok(vec![string("1/2")]));
+        assert_eq!(eval("1 2 make-rational 1 + to-string"), Ok(vec![string("3/2")]));
+        assert_eq!(eval("2 4 6 make-rational * to-string"), Ok(vec![string("4/3")]));
+        assert_eq!(eval(&format!("1 {} / to-string", half)), Ok(vec![string("2/1")]));
+        assert_eq!(eval(&format!("{} 0 / ", half)), Err(vm::Error::DivideByZero));
+        assert_eq!(stack_after_error(&format!("{} 0 / ", half)).len(), 2);
+        assert_eq!(stack_after_error(&format!("{} \"x\" +", half)).len(), 2);
+        assert_eq!(eval("1 0 make-rational"), Err(vm::Error::DivideByZero));
+        assert_eq!(eval(&format!("{} 1.0 +", half)), Err(vm::Error::TypeError));
+        assert_eq!(eval(&format!("{} as-float", half)), Ok(vec![StackItem::Float(0.5)]));
+        assert_eq!(eval("0.75 as-rational to-string 3 as-rational to-string"),
+            Ok(vec![string("3/4"), string("3/1")]));
+        assert_eq!(eval("4 2 make-rational 2 eq"), Ok(vec![StackItem::Boolean(true)]));
+        assert_eq!(eval(&format!("{} 1 3 make-rational min to-string", half)),
+            Ok(vec![string("1/3")]));
+        assert_eq!(eval(&format!("{} 1 max to-string {} positive?", half, half)),
+            Ok(vec![string("1/1"), StackItem::Boolean(true)]));
+        assert_eq!(eval("1 2 make-rational type-of"), Ok(vec![string("rational")]));
+        assert_eq!(eval(&format!("{} 0 1 clamp to-string 3 0 {} clamp to-string", half, half)),
+            Ok(vec![string("1/2"), string("1/2")]));
+        assert_eq!(eval(&format!("{} 1 0 clamp", half)), Err(vm::Error::OutOfBounds));
+        assert_eq!(eval(&format!("{} 0 1 between? 1 0 {} between?", half, half)),
+            Ok(vec![StackItem::Boolean(true), StackItem::Boolean(false)]));
+        assert_eq!(stack_after_error(&format!("{} 0.0 1 between?", half)).len(), 3);
+        assert_eq!(eval("7 2 make-rational as-integer 0 7 - 2 make-rational as-integer"),
+            Ok(vec![StackItem::Integer(3), StackItem::Integer(-3)]));
+    }
+
+    #[test]
+    fn test_fold() {
+        assert_eq!(eval("[1 2 3 4] 0 { + } fold"), Ok(vec![StackItem::Integer(10)]));
+        assert_eq!(eval("[] 7 { + } fold"), Ok(vec![StackItem::Integer(7)]));
+        assert_eq!(eval("9 [\"b\" \"c\"] \"a\" { cat } fold"),
+            Ok(vec![StackItem::Integer(9), string("abc")]));
+        assert_eq!(eval("[1 2] 0 { pop pop } fold"), Err(vm::Error::StackUnderflow));
+        assert_eq!(eval("1 0 { + } fold"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_filter() {
+        assert_eq!(eval("[1 2 3 4] { 2 - positive? } filter"), eval("[3 4]"));
+        assert_eq!(eval("[] { pop true } filter"), eval("[]"));
+        assert_eq!(eval("[1 2] { } filter"), Err(vm::Error::TypeError));
+        assert_eq!(eval("[1 2] { pop } filter"), Err(vm::Error::StackUnderflow));
+    }
+
+    #[test]
+    fn test_list_search() {
+        let t = StackItem::Boolean(true);
+        let f = StackItem::Boolean(false);
+        assert_eq!(eval("[1 \"a\" 2] \"a\" list-contains"), Ok(vec![t]));
+        assert_eq!(eval("[1 2] 3 list-contains [] 1 list-contains"),
+            Ok(vec![f.clone(), f.clone()]));
+        assert_eq!(eval("[1 2 3 2] 2 list-index-of"), Ok(vec![StackItem::Integer(1)]));
+        assert_eq!(eval("[1 2] 1.0 list-index-of"), Ok(vec![StackItem::Nil]));
+        assert_eq!(eval("1 1 list-contains"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_equality() {
+        let t = StackItem::Boolean(true);
+        let f = StackItem::Boolean(false);
+        assert_eq!(eval("{ 1 + } { 1 - } eq { 1 + } { 1 + } eq"),
+            Ok(vec![f.clone(), t.clone()]));
+        assert_eq!(eval("{ 1 + } { 1 - } deep-eq { 1 + } { 1 + } deep-eq"),
+            Ok(vec![f.clone(), t.clone()]));
+        assert_eq!(eval("nan nan eq nan nan deep-eq"), Ok(vec![f.clone(), t.clone()]));
+        assert_eq!(eval("[nan 1] [nan 1] deep-eq { [nan] } { [nan] } deep-eq"),
+            Ok(vec![t.clone(), t.clone()]));
+        assert_eq!(eval("[1] [1 1 make-rational] eq [1] [1 1 make-rational] deep-eq"),
+            Ok(vec![f.clone(), t.clone()]));
+        assert_eq!(eval("[1 2] [1] deep-eq 1 1.0 deep-eq"), Ok(vec![f.clone(), f]));
+    }
+
+    #[test]
+    fn test_sort() {
+        assert_eq!(eval("[3 1 2] sort"), eval("[1 2 3]"));
+        let sorted = |src: &str| eval(src).unwrap()[0].to_string();
+        assert_eq!(sorted("[2.5 1 nan 2 \"b\" \"a\" 'c' true nil] sort"),
+            "[nil true 1 2 2.5 NaN 'c' \"a\" \"b\"]");
+        assert_eq!(sorted("[1.0 0.0 0.0 / 0 1 - 2.0] sort"), "[-1 1.0 2.0 NaN]");
+        assert_eq!(sorted("[1.0 1 [2] [1 5] 1 2 make-rational] sort"),
+            "[1/2 1 1.0 [1 5] [2]]");
+        assert_eq!(eval("[] sort"), eval("[]"));
+        assert_eq!(eval("1 sort"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_between() {
+        assert_eq!(eval("5 0 10 between?"), Ok(vec![StackItem::Boolean(true)]));
+        assert_eq!(eval("15 0 10 between?"), Ok(vec![StackItem::Boolean(false)]));
+        assert_eq!(eval("0 0 10 between? 10 0 10 between?"),
+            Ok(vec![StackItem::Boolean(true), StackItem::Boolean(true)]));
+        assert_eq!(eval("nan 0.0 1.0 between?"), Ok(vec![StackItem::Boolean(false)]));
+        assert_eq!(eval("5 10 0 between?"), Err(vm::Error::OutOfBounds));
+        assert_eq!(stack_after_error("5 10 0 between?"),
+            vec![StackItem::Integer(5), StackItem::Integer(10), StackItem::Integer(0)]);
+        assert_eq!(eval("5 0 10.0 between?"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut vm = new_vm();
+        insert_eval(&mut vm);
+        assert_eq!(vm.run_block(&parse("\"2 3 +\" eval").unwrap()), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(5)]);
+        let define = parse("\":sq { clone * } fn\" eval 3 sq").unwrap();
+        assert_eq!(vm.run_block(&define), Ok(()));
+        assert_eq!(vm.stack.pop(), Ok(StackItem::Integer(9)));
+        assert_eq!(vm.run_block(&parse("\"1 {\" eval").unwrap()),
+            Err(vm::Error::ParseError("Unclosed block".to_string())));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(5), string("1 {")]);
+    }
+
+    #[test]
+    fn test_compile() {
+        let mut vm = new_vm();
+        insert_eval(&mut vm);
+        assert_eq!(vm.run_block(&parse("\"1 +\" compile 4 swap call").unwrap()), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(5)]);
+        assert_eq!(vm.run_block(&parse("\"x\" compile type-of").unwrap()), Ok(()));
+        assert_eq!(vm.stack.pop(), Ok(string("block")));
+        assert_eq!(vm.run_block(&parse("\"]\" compile").unwrap()),
+            Err(vm::Error::ParseError("Mismatched bracket".to_string())));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(5), string("]")]);
+    }
+
+    #[test]
+    fn test_quit() {
+        let mut vm = new_vm();
+        assert_eq!(vm.run_block(&parse("1 quit 2").unwrap()), Err(vm::Error::Quit));
+        assert_eq!(vm.quit_requested, Some(0));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(1)]);
+        let mut vm = new_vm();
+        assert_eq!(vm.run_block(&parse("{ 3 exit } try 2").unwrap()), Err(vm::Error::Quit));
+        assert_eq!(vm.quit_requested, Some(3));
+        assert!(vm.stack.0.is_empty());
+        assert_eq!(stack_after_error("99999999999 exit"), vec![StackItem::Integer(99999999999)]);
+    }
+
+    #[test]
+    fn test_fn_effect() {
+        assert_eq!(eval(":two { 1 2 } \"( -- a )\" fn-effect two"),
+            Err(vm::Error::StackEffectMismatch("two".to_string())));
+        assert_eq!(eval(":add { + } \"( a b -- c )\" fn-effect 1 2 add"),
+            Ok(vec![StackItem::Integer(3)]));
+        assert_eq!(eval(":f { } \"( a -- )\" fn-effect f"),
+            Err(vm::Error::StackEffectMismatch("f".to_string())));
+        assert_eq!(eval(":f { } \"a -- b\" fn-effect"),
+            Err(vm::Error::ParseError("a -- b".to_string())));
+        assert_eq!(eval(":+ { } \"( -- )\" fn-effect"),
+            Err(vm::Error::RedefineBuiltin("+".to_string())));
+        assert_eq!(stack_after_error(":+ { } \"( -- )\" fn-effect").len(), 3);
+        assert_eq!(stack_after_error(":+ protect :+ { } \"( -- )\" fn-effect")[2],
+            string("( -- )"));
+        assert_eq!(stack_after_error("{ } \"( -- )\" fn-effect").len(), 2);
+    }
+
+    #[test]
+    fn test_expect_depth() {
+        assert_eq!(eval("1 2 2 expect-depth"),
+            Ok(vec![StackItem::Integer(1), StackItem::Integer(2)]));
+        assert_eq!(eval("1 2 expect-depth"),
+            Err(vm::Error::UnexpectedDepth { expected: 2, found: 1 }));
+        assert_eq!(stack_after_error("1 2 5 expect-depth"),
+            vec![StackItem::Integer(1), StackItem::Integer(2), StackItem::Integer(5)]);
+        assert_eq!(stack_after_error("0 1 - expect-depth"), vec![StackItem::Integer(-1)]);
+    }
+
+    #[test]
+    fn test_locals() {
+        assert_eq!(eval(":sub { [:a :b] locals b a - } fn 10 3 sub"),
+            Ok(vec![StackItem::Integer(-7)]));
+        // Locals shadow methods, last only until the method returns and are
+        // seen by blocks it runs.
+        assert_eq!(eval(":f { [:pop] locals true { pop } if } fn 1 2 f pop"),
+            Ok(vec![StackItem::Integer(1)]));
+        assert_eq!(eval(":g { x } fn :f { [:x] locals g } fn 1 f"),
+            Err(vm::Error::UnknownMethod("x".to_string())));
+        assert_eq!(eval("1 [:a :b] locals"), Err(vm::Error::StackUnderflow));
+        assert_eq!(eval("1 [2] locals"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_tee() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = new_vm();
+        let log = seen.clone();
+        vm.insert_builtin_with_signature("observe", "( a -- )", Box::new(move |vm| {
+            log.borrow_mut().push(try!(vm.stack.pop()));
+            Ok(())
+        }));
+        vm.run_block(&parse("5 { observe 1 2 } tee").unwrap()).unwrap();
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(5)]);
+        assert_eq!(*seen.borrow(), vec![StackItem::Integer(5)]);
+        assert_eq!(stack_after_error("{ 1 } tee").len(), 1);
+        let stack = stack_after_error("5 { pop pop } tee");
+        assert_eq!(stack[0], StackItem::Integer(5));
+        assert!(matches!(stack[1], StackItem::Block(_)));
+        assert_eq!(stack.len(), 2);
+    }
+
+    #[test]
+    fn test_cat() {
+        assert_eq!(eval("\"a\" \"b\" cat"), Ok(vec![string("ab")]));
+        let mut vm = new_vm();
+        assert_eq!(vm.run_block(&parse("1 \"x\" cat").unwrap()),
+            Err(vm::Error::TypeError));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(1), string("x")]);
+    }
+
+    #[test]
+    fn test_set_nth() {
+        assert_eq!(eval("1 2 3 99 1 set-nth"),
+            Ok(vec![StackItem::Integer(1), StackItem::Integer(99), StackItem::Integer(3)]));
+        assert_eq!(eval("1 2 3 99 0 set-nth"),
+            Ok(vec![StackItem::Integer(1), StackItem::Integer(2), StackItem::Integer(99)]));
+        assert_eq!(eval("1 2 3 99 3 set-nth"), Err(vm::Error::OutOfBounds));
+        assert_eq!(eval("1 2 99 \"x\" set-nth"), Err(vm::Error::TypeError));
+        assert_eq!(stack_after_error("1 2 99 5 set-nth"),
+            vec![StackItem::Integer(1), StackItem::Integer(2), StackItem::Integer(99),
+                 StackItem::Integer(5)]);
+        assert_eq!(stack_after_error("0 set-nth"), vec![StackItem::Integer(0)]);
+    }
+
+    #[test]
+    fn test_join() {
+        assert_eq!(eval("[\"a\" \"b\" \"c\"] \",\" join"), Ok(vec![string("a,b,c")]));
+        assert_eq!(eval("[] \",\" join"), Ok(vec![string("")]));
+        assert_eq!(eval("[\"a\"] \", \" join"), Ok(vec![string("a")]));
+        assert_eq!(eval("[\"a\" 1] \",\" join"), Err(vm::Error::TypeError));
+        assert_eq!(eval("\"a\" \",\" join"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_collect_spread() {
+        assert_eq!(eval("1 2 3 collect"), eval("[1 2 3]"));
+        assert_eq!(eval("collect"), eval("[]"));
+        assert_eq!(eval("1 2 3 collect spread"), eval("1 2 3"));
+        assert_eq!(eval("0 [1 [2]] spread"), eval("0 1 [2]"));
+        assert_eq!(eval("1 spread"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_clone_shares_blocks() {
+        let large = format!("{{ {} }} clone", "1 ".repeat(10000));
+        let stack = eval(&large).unwrap();
+        match (&stack[0], &stack[1]) {
+            (StackItem::Block(a), StackItem::Block(b)) => assert!(Rc::ptr_eq(a, b)),
+            _ => panic!("expected two blocks"),
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(eval("5 \"sum is {}\" format"), Ok(vec![string("sum is 5")]));
+        assert_eq!(eval("3.0 to-string 3.0 \"{}\" format"),
+            Ok(vec![string("3.0"), string("3.0")]));
+        assert_eq!(eval("0 1 \"a\" 2.5 \"{}, {} and {}\" format"),
+            Ok(vec![StackItem::Integer(0), string("1, a and 2.5")]));
+        assert_eq!(eval("1 \"{{}} {} }} {\" format"), Ok(vec![string("{} 1 } {")]));
+        assert_eq!(eval("\"none\" format"), Ok(vec![string("none")]));
+        assert_eq!(eval("1 \"{} {}\" format"), Err(vm::Error::StackUnderflow));
+    }
+
+    #[test]
+    fn test_inc_dec() {
+        assert_eq!(eval("5 inc"), Ok(vec![StackItem::Integer(6)]));
+        assert_eq!(eval("5 dec"), Ok(vec![StackItem::Integer(4)]));
+        assert_eq!(eval("0.5 inc 0.5 dec"),
+            Ok(vec![StackItem::Float(1.5), StackItem::Float(-0.5)]));
+        assert_eq!(eval("9223372036854775807 inc"), Err(vm::Error::IntegerOverflow));
+        assert_eq!(eval("\"5\" inc"), Err(vm::Error::TypeError));
+        assert_eq!(eval("1 2 make-rational inc to-string 1 2 make-rational dec to-string"),
+            Ok(vec![string("3/2"), string("-1/2")]));
+        assert_eq!(stack_after_error("9223372036854775807 inc"),
+            vec![StackItem::Integer(i64::MAX)]);
+    }
+
+    #[test]
+    fn test_keep_nth() {
+        assert_eq!(eval("10 20 30 1 keep-nth"), Ok(vec![StackItem::Integer(20)]));
+        assert_eq!(eval("10 20 30 0 keep-nth"), Ok(vec![StackItem::Integer(30)]));
+        assert_eq!(eval("10 20 30 3 keep-nth"), Err(vm::Error::OutOfBounds));
+        assert_eq!(stack_after_error("10 20 2 keep-nth"),
+            vec![StackItem::Integer(10), StackItem::Integer(20), StackItem::Integer(2)]);
+        assert_eq!(stack_after_error("10 \"a\" keep-nth"),
+            vec![StackItem::Integer(10), string("a")]);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let ints = |v: &[i64]| v.iter().map(|&n| StackItem::Integer(n)).collect::<Vec<_>>();
+        assert_eq!(eval("1 2 3 4 1 rotate"), Ok(ints(&[4, 1, 2, 3])));
+        assert_eq!(eval("1 2 3 4 0 1 - rotate"), Ok(ints(&[2, 3, 4, 1])));
+        assert_eq!(eval("1 2 3 4 6 rotate"), Ok(ints(&[3, 4, 1, 2])));
+        assert_eq!(eval("3 rotate"), Ok(vec![]));
+        assert_eq!(stack_after_error("1 \"x\" rotate"), vec![StackItem::Integer(1), string("x")]);
+    }
+
+    #[test]
+    fn test_min_max_by() {
+        assert_eq!(eval("[\"a\" \"ccc\" \"bb\"] { str-len } max-by"),
+                   Ok(vec![string("ccc")]));
+        assert_eq!(eval("[\"a\" \"ccc\" \"bb\"] { str-len } min-by"),
+                   Ok(vec![string("a")]));
+        assert_eq!(eval("[3 1 2] { } min-by"), Ok(vec![StackItem::Integer(1)]));
+        assert_eq!(eval("[] { } max-by"), Err(vm::Error::OutOfBounds));
+        assert_eq!(eval("[1 \"a\"] { } max-by"), Err(vm::Error::TypeError));
+        assert_eq!(eval("[1 2] { 1 swap make-rational } max-by"),
+            Ok(vec![StackItem::Integer(1)]));
+        assert_eq!(stack_after_error("[] { } max-by").len(), 2);
+        let stack = stack_after_error("0 [1 \"a\"] { } max-by");
+        assert_eq!(stack[0], StackItem::Integer(0));
+        assert!(matches!(stack[1], StackItem::List(_)));
+        assert!(matches!(stack[2], StackItem::Block(_)));
+        assert_eq!(stack.len(), 3);
+    }
+
+    #[test]
+    fn test_control_flow_type_error_keeps_operands() {
+        assert_eq!(eval("0 1 { if } try"),
+            Ok(vec![StackItem::Integer(0), StackItem::Integer(1),
+                    StackItem::Error(vm::Error::TypeError), StackItem::Boolean(false)]));
+        let mut vm = new_vm();
+        assert_eq!(vm.run_block(&parse("1 { } { } ifelse").unwrap()),
+            Err(vm::Error::TypeError));
+        assert_eq!(vm.stack.0.len(), 3);
+        assert_eq!(vm.stack.0[0], StackItem::Integer(1));
+        let mut vm = new_vm();
+        assert_eq!(vm.run_block(&parse("{ 7 } { } while").unwrap()),
+            Err(vm::Error::TypeError));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(7)]);
+        let mut vm = new_vm();
+        assert_eq!(vm.run_block(&parse("true { } times").unwrap()),
+            Err(vm::Error::TypeError));
+        assert_eq!(vm.stack.0[0], StackItem::Boolean(true));
+    }
+
+    #[test]
+    fn test_protect() {
+        assert_eq!(eval(":f { 1 } fn :f { 2 } fn f"), Ok(vec![StackItem::Integer(2)]));
+        assert_eq!(eval(":+ protect :+ { } fn"),
+                   Err(vm::Error::Protected("+".to_string())));
+        assert_eq!(eval(":f { 1 } fn :f protect :f { 2 } fn"),
+                   Err(vm::Error::Protected("f".to_string())));
+        assert_eq!(eval("1 protect"), Err(vm::Error::TypeError));
+        for src in &[":+ protect :+ { } fn!", ":+ { } fn", "1 { } fn"] {
+            let stack = stack_after_error(src);
+            assert_eq!(stack.len(), 2);
+            assert!(matches!(stack[1], StackItem::Block(_)));
+        }
+    }
+
+    #[test]
+    fn test_call() {
+        assert_eq!(eval("{ 2 3 + } call"), Ok(vec![StackItem::Integer(5)]));
+        assert_eq!(eval("1 call"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_compose() {
+        assert_eq!(eval("4 { 1 } { + } compose call"), Ok(vec![StackItem::Integer(5)]));
+        assert_eq!(eval("{ 1 } 2 compose"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_nil() {
+        assert_eq!(eval("nil"), Ok(vec![StackItem::Nil]));
+        assert_eq!(eval("nil nil?"), Ok(vec![StackItem::Boolean(true)]));
+        assert_eq!(eval("0 nil?"), Ok(vec![StackItem::Boolean(false)]));
+        assert_eq!(eval("nil nil eq"), Ok(vec![StackItem::Boolean(true)]));
+        assert_eq!(eval("nil false eq"), Ok(vec![StackItem::Boolean(false)]));
+    }
+
+    #[test]
+    fn test_parse_numbers() {
+        assert_eq!(eval("\"ff\" 16 parse-int"), Ok(vec![StackItem::Integer(255)]));
+        assert_eq!(eval("\"42\" 10 parse-int"), Ok(vec![StackItem::Integer(42)]));
+        assert_eq!(eval("\"zz\" 10 parse-int"), Err(vm::Error::NumericConversion));
+        assert_eq!(eval("\"1\" 99 parse-int"), Err(vm::Error::OutOfBounds));
+        assert_eq!(eval("\"2.5\" parse-float"), Ok(vec![StackItem::Float(2.5)]));
+        assert_eq!(eval("\"x\" parse-float"), Err(vm::Error::NumericConversion));
+        assert_eq!(stack_after_error("\"zz\" 16 parse-int"),
+            vec![string("zz"), StackItem::Integer(16)]);
+        assert_eq!(stack_after_error("\"1\" 99 parse-int"),
+            vec![string("1"), StackItem::Integer(99)]);
+        assert_eq!(stack_after_error("\"x\" parse-float"), vec![string("x")]);
+    }
+
+    #[test]
+    fn test_min_max() {
+        assert_eq!(eval("3 7 max"), Ok(vec![StackItem::Integer(7)]));
+        assert_eq!(eval("3 7 min"), Ok(vec![StackItem::Integer(3)]));
+        assert_eq!(eval("2.5 1.5 max"), Ok(vec![StackItem::Float(2.5)]));
+        assert_eq!(eval("2.5 1.5 min"), Ok(vec![StackItem::Float(1.5)]));
+        assert_eq!(eval("1 1.5 min"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_jump() {
+        assert_eq!(eval("1 [{ \"a\" } { \"b\" } { \"c\" }] jump"),
+            Ok(vec![string("b")]));
+        assert_eq!(eval("3 [{ \"a\" } { \"b\" } { \"c\" }] jump"),
+            Err(vm::Error::OutOfBounds));
+        assert_eq!(eval("0 [1] jump"), Err(vm::Error::TypeError));
+        assert_eq!(stack_after_error("0 [1] jump"),
+            vec![StackItem::Integer(0), StackItem::List(vec![StackItem::Integer(1)])]);
+        assert_eq!(stack_after_error("5 [{ }] jump").len(), 2);
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        assert_eq!(eval("9223372036854775807 1 +"), Err(vm::Error::IntegerOverflow));
+        assert_eq!(eval("0 9223372036854775807 - 2 - 1 -"),
+                   Err(vm::Error::IntegerOverflow));
+        assert_eq!(eval("4611686018427387904 2 *"), Err(vm::Error::IntegerOverflow));
+        assert_eq!(eval("9223372036854775806 1 +"),
+                   Ok(vec![StackItem::Integer(i64::MAX)]));
+    }
+
+    #[test]
+    fn test_auto_promote() {
+        assert_eq!(eval("2 3.0 +"), Err(vm::Error::TypeError));
+        let mut vm = new_vm();
+        vm.auto_promote = true;
+        assert_eq!(vm.run_block(&parse("2 3.0 + 1.5 3 * 7 2 /").unwrap()), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Float(5.0), StackItem::Float(4.5),
+                                    StackItem::Integer(3)]);
+    }
+
+    #[test]
+    fn test_gcd_lcm() {
+        assert_eq!(eval("12 18 gcd"), Ok(vec![StackItem::Integer(6)]));
+        assert_eq!(eval("4 6 lcm"), Ok(vec![StackItem::Integer(12)]));
+        assert_eq!(eval("4.0 6.0 gcd"), Err(vm::Error::TypeError));
+        assert_eq!(eval("0 12 - 18 gcd 12 0 18 - gcd 0 0 12 - gcd 0 0 gcd"),
+            Ok(vec![StackItem::Integer(6), StackItem::Integer(6),
+                    StackItem::Integer(12), StackItem::Integer(0)]));
+        assert_eq!(eval("0 4 - 6 lcm 4 0 6 - lcm 0 5 lcm"),
+            Ok(vec![StackItem::Integer(12), StackItem::Integer(12),
+                    StackItem::Integer(0)]));
+    }
+
+    #[test]
+    fn test_gcd_lcm_overflow() {
+        let min = "0 9223372036854775807 - 1 -";
+        assert_eq!(eval("4611686018427387904 3 lcm"), Err(vm::Error::IntegerOverflow));
+        assert_eq!(eval(&format!("{} 0 gcd", min)), Err(vm::Error::IntegerOverflow));
+        assert_eq!(eval(&format!("{} clone gcd", min)), Err(vm::Error::IntegerOverflow));
+        assert_eq!(eval(&format!("{} 1 lcm", min)), Err(vm::Error::IntegerOverflow));
+        assert_eq!(eval(&format!("{} 0 1 - gcd {} 6 gcd", min, min)),
+            Ok(vec![StackItem::Integer(1), StackItem::Integer(2)]));
+        assert_eq!(eval(&format!("{} 0 lcm", min)), Ok(vec![StackItem::Integer(0)]));
+    }
+
+    #[test]
+    fn test_float_math() {
+        assert_eq!(eval("2.0 sqrt"), Ok(vec![StackItem::Float(2.0f64.sqrt())]));
+        assert_eq!(eval("2.7 floor"), Ok(vec![StackItem::Float(2.0)]));
+        assert_eq!(eval("2.2 ceil"), Ok(vec![StackItem::Float(3.0)]));
+        assert_eq!(eval("2.5 round"), Ok(vec![StackItem::Float(3.0)]));
+        match eval("0.0 1.0 - sqrt").unwrap()[0] {
+            StackItem::Float(f) => assert!(f.is_nan()),
+            ref other => panic!("expected a float, got {:?}", other),
+        }
+        assert_eq!(eval("4 sqrt"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_last_error() {
+        assert_eq!(eval("last-error"), Ok(vec![StackItem::Nil]));
+        assert_eq!(eval("rethrow"), Ok(vec![]));
+        assert_eq!(eval("{ 1 0 / } try pop pop last-error error-kind"),
+            Ok(vec![StackItem::Symbol("divide-by-zero".to_string())]));
+        assert_eq!(eval("{ 1 0 / } try pop pop rethrow"),
+            Err(vm::Error::DivideByZero));
+        assert_eq!(eval("1 error-kind"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_error_message() {
+        let mut vm = new_vm();
+        vm.capture_output();
+        let program = parse("{ 1 \"a\" + } try pop clone error? swap error-message print");
+        assert_eq!(vm.run_block(&program.unwrap()), Ok(()));
+        assert_eq!(vm.take_output(), "Type error\n");
+        assert_eq!(vm.stack.0, vec![StackItem::Boolean(true)]);
+        assert_eq!(eval("\"Type error\" error?"), Ok(vec![StackItem::Boolean(false)]));
+        assert_eq!(eval("1 error-message"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_transcendental() {
+        assert_eq!(eval("0.0 cos"), Ok(vec![StackItem::Float(1.0)]));
+        assert_eq!(eval("0.0 sin"), Ok(vec![StackItem::Float(0.0)]));
+        assert_eq!(eval("0.0 exp"), Ok(vec![StackItem::Float(1.0)]));
+        assert_eq!(eval("100.0 log10"), Ok(vec![StackItem::Float(2.0)]));
+        match eval("0.0 1.0 - ln").unwrap()[0] {
+            StackItem::Float(f) => assert!(f.is_nan()),
+            ref other => panic!("expected a float, got {:?}", other),
+        }
+        assert_eq!(eval("0 cos"), Err(vm::Error::TypeError));
+    }
+
+    #[test]
+    fn test_signatures() {
+        let vm = new_vm();
+        assert_eq!(vm.signature("swap"), Some("( a b -- b a )"));
+        assert_eq!(vm.signature("sqrt"), Some("( f -- f )"));
+        assert_eq!(vm.signature("no-such-method"), None);
+    }
+
+    #[test]
+    fn test_float_constants() {
+        match eval("pi").unwrap()[0] {
+            StackItem::Float(f) => assert_eq!((f * 1e5).round(), 314159.0),
+            ref other => panic!("expected a float, got {:?}", other),
+        }
+        assert_eq!(eval("e"), Ok(vec![StackItem::Float(::std::f64::consts::E)]));
+        assert_eq!(eval("inf"), Ok(vec![StackItem::Float(f64::INFINITY)]));
+        match eval("nan").unwrap()[0] {
+            StackItem::Float(f) => assert!(f.is_nan()),
+            ref other => panic!("expected a float, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_repr() {
+        match eval("{ 1 2 } repr").unwrap()[0] {
+            StackItem::String(ref s) => assert!(s.contains("Block")),
+            ref other => panic!("expected a string, got {:?}", other),
+        }
+        assert_eq!(eval("\"a\" repr"), Ok(vec![string("String(\"a\")")]));
+    }
+
+    #[test]
+    fn test_show_stack() {
+        let mut vm = new_vm();
+        vm.capture_output();
+        vm.run_block(&parse("1 2 3 show-stack \"x\" print").unwrap()).unwrap();
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(1), StackItem::Integer(2),
+                                    StackItem::Integer(3)]);
+        assert_eq!(vm.take_output(), "1 2 3 \nx\n");
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let mut vm = new_vm();
+        vm.capture_output();
+        vm.dry_run = true;
+        assert_eq!(vm.run_block(&parse("1 2 show-stack \"x\" print").unwrap()), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(1), StackItem::Integer(2)]);
+        assert_eq!(vm.take_output(), "");
+        assert_eq!(vm.run_block(&parse("true { undefined } if").unwrap()),
+                   Err(vm::Error::UnknownMethod("undefined".to_string())));
+    }
+
+    #[test]
+    fn test_dry_run_fs() {
+        let path = env::temp_dir().join(format!("rustpn-test-dry-run-{}", process::id()));
+        let mut vm = new_vm();
+        insert_fs(&mut vm);
+        vm.dry_run = true;
+        vm.inject(&[string("contents"), string(path.to_str().unwrap())]).unwrap();
+        assert_eq!(vm.run_block(&parse("write-file").unwrap()), Ok(()));
+        assert!(vm.stack.0.is_empty());
+        assert!(!path.exists());
+        vm.inject(&[string(path.to_str().unwrap())]).unwrap();
+        assert_eq!(vm.run_block(&parse("read-file").unwrap()), Ok(()));
+        assert_eq!(vm.stack.0, vec![string("")]);
+    }
+
+    #[test]
+    fn test_version() {
+        assert_eq!(eval("version"), Ok(vec![string(env!("CARGO_PKG_VERSION"))]));
+        assert_eq!(eval("\"0\" version-at-least?"), Ok(vec![StackItem::Boolean(true)]));
+        assert_eq!(eval("version version-at-least?"), Ok(vec![StackItem::Boolean(true)]));
+        assert_eq!(eval("\"99.0\" version-at-least?"),
+            Ok(vec![StackItem::Boolean(false)]));
+        assert_eq!(eval("\"one\" version-at-least?"), Err(vm::Error::NumericConversion));
+        assert_eq!(stack_after_error("\"one\" version-at-least?"), vec![string("one")]);
+    }
+
+    #[test]
+    fn test_swap_nth() {
+        assert_eq!(eval("1 2 3 2 swap-nth"),
+            Ok(vec![StackItem::Integer(3), StackItem::Integer(2), StackItem::Integer(1)]));
+        assert_eq!(eval("1 2 3 0 swap-nth"),
+            Ok(vec![StackItem::Integer(1), StackItem::Integer(2), StackItem::Integer(3)]));
+        assert_eq!(eval("1 2 3 3 swap-nth"), Err(vm::Error::OutOfBounds));
+        assert_eq!(stack_after_error("1 2 3 5 swap-nth"),
+            vec![StackItem::Integer(1), StackItem::Integer(2), StackItem::Integer(3),
+                 StackItem::Integer(5)]);
+        assert_eq!(stack_after_error("1 \"x\" swap-nth"),
+            vec![StackItem::Integer(1), string("x")]);
+    }
 }
@@ -1,6 +1,13 @@
 //! Language items and abstract-syntax tree.
 
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::f64;
 use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use num::{zero, Integer, ToPrimitive, FromPrimitive, Signed};
+use num::rational::BigRational;
 use vm;
 
 /// The equivalent of a routine/function.
@@ -12,6 +19,9 @@ pub struct Block<I>(pub Vec<BlockItem<I>>);
 pub enum BlockItem<I> {
     Call(String),
     Literal(StackItem<I>),
+    /// A list literal; its contents are run and whatever they push is
+    /// collected into a `StackItem::List`.
+    List(Block<I>),
 }
 
 impl<I> fmt::Display for Block<I> where I: fmt::Display {
@@ -28,21 +38,291 @@ impl<I> fmt::Display for BlockItem<I> where I: fmt::Display {
         match *self {
             BlockItem::Call(ref s) => write!(f, "{}", *s),
             BlockItem::Literal(ref s) => write!(f, "{}", *s),
+            BlockItem::List(ref b) => write_list(f, &b.0),
         }
     }
 }
 
-/// The global stack.
+// Lists are written like their literals, e.g. `[1 2 3]`.
+fn write_list<T>(f: &mut fmt::Formatter, items: &[T]) -> fmt::Result
+        where T: fmt::Display {
+    try!(write!(f, "["));
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            try!(write!(f, " "));
+        }
+        try!(write!(f, "{}", item));
+    }
+    write!(f, "]")
+}
+
+/// The global stack, along with the most items it may hold.
+///
+/// The stack keeps a running estimate of its size for `approx_size`, which
+/// only sees items added and removed through its methods. After changing
+/// `.0` directly in a way that adds or drops items, call `recount`.
 #[derive(PartialEq, Clone, Debug)]
-pub struct Stack<I>(pub Vec<StackItem<I>>);
+pub struct Stack<I>(pub Vec<StackItem<I>>, usize, Footprint<I>);
+
+// Running estimate of the memory held by the items on a stack. A block on the
+// stack more than once shares one allocation, so its contents count once.
+#[derive(Clone, Debug)]
+struct Footprint<I> {
+    size: usize,
+    blocks: HashMap<*const Block<I>, usize>,
+}
+
+impl<I> Footprint<I> {
+    fn new() -> Footprint<I> {
+        Footprint { size: 0, blocks: HashMap::new() }
+    }
+
+    fn add(&mut self, item: &StackItem<I>) {
+        if let StackItem::Block(ref b) = *item {
+            let count = self.blocks.entry(&**b as *const _).or_insert(0);
+            *count += 1;
+            if *count > 1 {
+                self.size += mem::size_of::<StackItem<I>>();
+                return;
+            }
+        }
+        self.size += item.approx_size();
+    }
+
+    fn remove(&mut self, item: &StackItem<I>) {
+        if let StackItem::Block(ref b) = *item {
+            let ptr = &**b as *const _;
+            let shared = match self.blocks.get_mut(&ptr) {
+                Some(count) => {
+                    *count -= 1;
+                    *count > 0
+                },
+                None => false,
+            };
+            if shared {
+                self.size -= mem::size_of::<StackItem<I>>();
+                return;
+            }
+            self.blocks.remove(&ptr);
+        }
+        self.size = self.size.saturating_sub(item.approx_size());
+    }
+}
+
+// The estimate is a cache of the items, so it plays no part in equality.
+impl<I> PartialEq for Footprint<I> {
+    fn eq(&self, _: &Footprint<I>) -> bool {
+        true
+    }
+}
+
+/// Default for the most items a stack may hold.
+pub const DEFAULT_MAX_STACK: usize = 1 << 20;
+
+impl<I> BlockItem<I> {
+    /// Rough estimate of the memory held by this item, in bytes.
+    pub fn approx_size(&self) -> usize {
+        match *self {
+            BlockItem::Call(ref s) => mem::size_of::<BlockItem<I>>() + s.len(),
+            BlockItem::Literal(ref item) => item.approx_size(),
+            BlockItem::List(ref b) => mem::size_of::<BlockItem<I>>()
+                + b.0.iter().map(|item| item.approx_size()).sum::<usize>(),
+        }
+    }
+}
 
 impl<I> Stack<I> {
+    pub fn new() -> Stack<I> {
+        Stack::with_max(DEFAULT_MAX_STACK)
+    }
+
+    /// Create a stack holding at most `max` items.
+    pub fn with_max(max: usize) -> Stack<I> {
+        Stack(Vec::new(), max, Footprint::new())
+    }
+
+    pub fn max(&self) -> usize {
+        self.1
+    }
+
+    pub fn set_max(&mut self, max: usize) {
+        self.1 = max;
+    }
+
+    /// The items on the stack, bottom first.
+    pub fn as_slice(&self) -> &[StackItem<I>] {
+        &self.0
+    }
+
     pub fn pop(&mut self) -> vm::Result<StackItem<I>> {
-        self.0.pop().ok_or(vm::Error::StackUnderflow)
+        let item = try!(self.0.pop().ok_or(vm::Error::StackUnderflow));
+        self.2.remove(&item);
+        Ok(item)
+    }
+
+    /// Pop the top two items, returned in stack order so the top is last.
+    /// Nothing is popped if there are too few items.
+    pub fn pop2(&mut self) -> vm::Result<(StackItem<I>, StackItem<I>)> {
+        if self.0.len() < 2 {
+            return Err(vm::Error::StackUnderflow);
+        }
+        let b = self.pop().unwrap();
+        let a = self.pop().unwrap();
+        Ok((a, b))
+    }
+
+    /// Pop the top three items, returned in stack order like `pop2`.
+    pub fn pop3(&mut self) -> vm::Result<(StackItem<I>, StackItem<I>, StackItem<I>)> {
+        if self.0.len() < 3 {
+            return Err(vm::Error::StackUnderflow);
+        }
+        let c = self.pop().unwrap();
+        let (a, b) = try!(self.pop2());
+        Ok((a, b, c))
     }
 
-    pub fn push(&mut self, item: StackItem<I>) {
+    // Pop the top item if `unwrap` accepts it. Otherwise it is left on the
+    // stack and the result is a type error.
+    fn pop_as<T, F>(&mut self, unwrap: F) -> vm::Result<T>
+            where F: FnOnce(StackItem<I>) -> Result<T, StackItem<I>> {
+        match unwrap(try!(self.pop())) {
+            Ok(value) => Ok(value),
+            Err(item) => {
+                self.2.add(&item);
+                self.0.push(item);
+                Err(vm::Error::TypeError)
+            },
+        }
+    }
+
+    /// Pop an integer. If the top is anything else it stays on the stack and
+    /// the result is `TypeError`; the same goes for the other `pop_*` methods.
+    pub fn pop_integer(&mut self) -> vm::Result<I> {
+        self.pop_as(|item| match item {
+            StackItem::Integer(n) => Ok(n),
+            other => Err(other),
+        })
+    }
+
+    pub fn pop_float(&mut self) -> vm::Result<f64> {
+        self.pop_as(|item| match item {
+            StackItem::Float(n) => Ok(n),
+            other => Err(other),
+        })
+    }
+
+    pub fn pop_string(&mut self) -> vm::Result<String> {
+        self.pop_as(|item| match item {
+            StackItem::String(s) => Ok(s),
+            other => Err(other),
+        })
+    }
+
+    pub fn pop_char(&mut self) -> vm::Result<char> {
+        self.pop_as(|item| match item {
+            StackItem::Char(c) => Ok(c),
+            other => Err(other),
+        })
+    }
+
+    pub fn pop_bool(&mut self) -> vm::Result<bool> {
+        self.pop_as(|item| match item {
+            StackItem::Boolean(b) => Ok(b),
+            other => Err(other),
+        })
+    }
+
+    pub fn pop_symbol(&mut self) -> vm::Result<String> {
+        self.pop_as(|item| match item {
+            StackItem::Symbol(s) => Ok(s),
+            other => Err(other),
+        })
+    }
+
+    pub fn pop_block(&mut self) -> vm::Result<Rc<Block<I>>> {
+        self.pop_as(|item| match item {
+            StackItem::Block(b) => Ok(b),
+            other => Err(other),
+        })
+    }
+
+    /// Remove and return every item, bottom first.
+    pub fn drain(&mut self) -> Vec<StackItem<I>> {
+        self.2 = Footprint::new();
+        mem::take(&mut self.0)
+    }
+
+    /// Remove every item.
+    pub fn clear(&mut self) {
+        self.drain();
+    }
+
+    /// Remove the items above the first `len`, as for `Vec::truncate`.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.0.len() {
+            self.split_off(len);
+        }
+    }
+
+    /// Remove and return the items from index `at` up, bottom first, as for
+    /// `Vec::split_off`.
+    pub fn split_off(&mut self, at: usize) -> Vec<StackItem<I>> {
+        let items = self.0.split_off(at);
+        for item in &items {
+            self.2.remove(item);
+        }
+        items
+    }
+
+    /// Replace the item at `index`, counting from the bottom, returning the
+    /// old one.
+    pub fn replace(&mut self, index: usize, item: StackItem<I>) -> StackItem<I> {
+        self.2.add(&item);
+        let old = mem::replace(&mut self.0[index], item);
+        self.2.remove(&old);
+        old
+    }
+
+    /// Recompute the size estimate after `.0` was changed directly.
+    pub fn recount(&mut self) {
+        let mut footprint = Footprint::new();
+        for item in &self.0 {
+            footprint.add(item);
+        }
+        self.2 = footprint;
+    }
+
+    /// Borrow the top item without popping it.
+    pub fn peek(&self) -> vm::Result<&StackItem<I>> {
+        self.0.last().ok_or(vm::Error::StackUnderflow)
+    }
+
+    /// Borrow the top item to change it in place. The size estimate does not
+    /// see such changes until `recount`.
+    pub fn peek_mut(&mut self) -> vm::Result<&mut StackItem<I>> {
+        self.0.last_mut().ok_or(vm::Error::StackUnderflow)
+    }
+
+    pub fn push(&mut self, item: StackItem<I>) -> vm::Result<()> {
+        if self.0.len() >= self.1 {
+            return Err(vm::Error::StackOverflow);
+        }
+        self.2.add(&item);
         self.0.push(item);
+        Ok(())
+    }
+
+    /// Rough estimate of the memory held by all items on the stack, in
+    /// bytes. This is kept up to date as items are pushed and popped, so it
+    /// is cheap to call.
+    pub fn approx_size(&self) -> usize {
+        self.2.size
+    }
+}
+
+impl<I> Default for Stack<I> {
+    fn default() -> Stack<I> {
+        Stack::new()
     }
 }
 
@@ -60,21 +340,345 @@ impl<I> fmt::Display for Stack<I> where I: fmt::Display {
 pub enum StackItem<I> {
     Integer(I),
     Float(f64),
+    /// An exact fraction, whatever the integer type.
+    Rational(BigRational),
     String(String),
+    Char(char),
     Boolean(bool),
     Symbol(String),
-    Block(Block<I>),
+    /// Shared, so that pushing a block literal does not copy the block.
+    Block(Rc<Block<I>>),
+    List(Vec<StackItem<I>>),
+    Error(vm::Error),
+    Nil,
+}
+
+impl<I> StackItem<I> {
+    /// Name of the item's runtime type, as reported by `type-of`.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            StackItem::Integer(_) => "integer",
+            StackItem::Float(_) => "float",
+            StackItem::Rational(_) => "rational",
+            StackItem::String(_) => "string",
+            StackItem::Char(_) => "char",
+            StackItem::Boolean(_) => "boolean",
+            StackItem::Symbol(_) => "symbol",
+            StackItem::Block(_) => "block",
+            StackItem::List(_) => "list",
+            StackItem::Error(_) => "error",
+            StackItem::Nil => "nil",
+        }
+    }
+
+    /// Rough estimate of the memory held by this item, in bytes.
+    ///
+    /// This counts the item itself plus string contents and nested blocks.
+    /// Heap storage owned by the integer type (e.g. `BigInt` digits) or by
+    /// rationals is not visible here and is not counted.
+    pub fn approx_size(&self) -> usize {
+        let heap = match *self {
+            StackItem::String(ref s) | StackItem::Symbol(ref s) => s.len(),
+            StackItem::Block(ref b) =>
+                b.0.iter().map(|item| item.approx_size()).sum(),
+            StackItem::List(ref l) =>
+                l.iter().map(|item| item.approx_size()).sum(),
+            _ => 0,
+        };
+        mem::size_of::<StackItem<I>>() + heap
+    }
+}
+
+// A number's value as a float for comparing across numeric types. Values too
+// large to convert become infinities of the same sign.
+fn approx_f64<T>(n: &T, negative: bool) -> f64 where T: ToPrimitive {
+    n.to_f64().unwrap_or(if negative { f64::NEG_INFINITY } else { f64::INFINITY })
+}
+
+impl<I> StackItem<I> where I: Integer + ToPrimitive {
+    // Position of the item's type in the order used by `total_cmp`; all
+    // numbers share one position so that they compare by value.
+    fn type_rank(&self) -> u8 {
+        match *self {
+            StackItem::Nil => 0,
+            StackItem::Boolean(_) => 1,
+            StackItem::Integer(_) | StackItem::Float(_) | StackItem::Rational(_) => 2,
+            StackItem::Char(_) => 3,
+            StackItem::String(_) => 4,
+            StackItem::Symbol(_) => 5,
+            StackItem::List(_) => 6,
+            StackItem::Block(_) => 7,
+            StackItem::Error(_) => 8,
+        }
+    }
+
+    fn numeric_value(&self) -> Option<f64> {
+        match *self {
+            StackItem::Integer(ref n) => Some(approx_f64(n, *n < zero())),
+            StackItem::Float(f) => Some(f),
+            StackItem::Rational(ref r) => {
+                let numer = approx_f64(r.numer(), r.is_negative());
+                Some(match r.denom().to_f64() {
+                    _ if !numer.is_finite() => numer,
+                    Some(denom) => numer / denom,
+                    None => 0.0,
+                })
+            },
+            _ => None,
+        }
+    }
+
+    /// Order any two items, as used by `sort`.
+    ///
+    /// Items of different types are ordered nil, booleans, numbers, chars,
+    /// strings, symbols, lists, blocks and then errors. Numbers compare by
+    /// value whatever their type, with NaN after every other number, and ties
+    /// between types put integers before floats before rationals. Strings
+    /// and symbols compare lexicographically, lists element by element and
+    /// errors by kind; all blocks compare equal.
+    pub fn total_cmp(&self, other: &StackItem<I>) -> Ordering {
+        match (self, other) {
+            (StackItem::Integer(a), StackItem::Integer(b)) => a.cmp(b),
+            (StackItem::Rational(a), StackItem::Rational(b)) => a.cmp(b),
+            (StackItem::Boolean(a), StackItem::Boolean(b)) => a.cmp(b),
+            (StackItem::Char(a), StackItem::Char(b)) => a.cmp(b),
+            (StackItem::String(a), StackItem::String(b))
+                | (StackItem::Symbol(a), StackItem::Symbol(b)) => a.cmp(b),
+            (StackItem::List(a), StackItem::List(b)) => {
+                for (a, b) in a.iter().zip(b) {
+                    match a.total_cmp(b) {
+                        Ordering::Equal => {},
+                        unequal => return unequal,
+                    }
+                }
+                a.len().cmp(&b.len())
+            },
+            (StackItem::Error(a), StackItem::Error(b)) => a.kind().cmp(b.kind()),
+            _ => match (self.numeric_value(), other.numeric_value()) {
+                // Every NaN sorts last, whatever its sign bit.
+                (Some(a), Some(b)) => match (a.is_nan(), b.is_nan()) {
+                    (false, false) => a.total_cmp(&b),
+                    (a_nan, b_nan) => a_nan.cmp(&b_nan),
+                }.then(self.numeric_rank().cmp(&other.numeric_rank())),
+                _ => self.type_rank().cmp(&other.type_rank()),
+            },
+        }
+    }
+
+    // Tie-break between numbers of different types with the same value.
+    fn numeric_rank(&self) -> u8 {
+        match *self {
+            StackItem::Integer(_) => 0,
+            StackItem::Float(_) => 1,
+            _ => 2,
+        }
+    }
 }
 
 impl<I> fmt::Display for StackItem<I> where I: fmt::Display {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             StackItem::Integer(ref i) => write!(f, "{}", *i),
+            // Always show a decimal point so integral floats such as `3.0`
+            // read back as floats rather than integers.
+            StackItem::Float(n) if n.is_finite() && n.fract() == 0.0 =>
+                write!(f, "{:.1}", n),
             StackItem::Float(n) => write!(f, "{}", n),
+            // Always show the denominator, so `2/1` is not taken for an integer.
+            StackItem::Rational(ref r) => write!(f, "{}/{}", r.numer(), r.denom()),
             StackItem::String(ref s) => write!(f, "\"{}\"", *s),
+            StackItem::Char(c) => write!(f, "'{}'", c),
             StackItem::Boolean(b) => write!(f, "{}", b),
             StackItem::Symbol(ref s) => write!(f, ":{}", *s),
             StackItem::Block(ref b) => write!(f, "{{ {}}}", *b),
+            StackItem::List(ref l) => write_list(f, l),
+            StackItem::Error(ref e) => write!(f, "<error: {}>", *e),
+            StackItem::Nil => write!(f, "nil"),
         }
     }
 }
+
+/// Rust values which can be read from a stack item, for host functions
+/// registered with `Vm::register1` and `Vm::register2`.
+pub trait FromStackItem<I>: Sized {
+    /// `None` if the item is of another type or out of range.
+    fn from_stack_item(item: &StackItem<I>) -> Option<Self>;
+}
+
+/// Rust values which can be pushed as a stack item.
+pub trait IntoStackItem<I> {
+    fn into_stack_item(self) -> vm::Result<StackItem<I>>;
+}
+
+impl<I> FromStackItem<I> for i64 where I: ToPrimitive {
+    fn from_stack_item(item: &StackItem<I>) -> Option<i64> {
+        match *item {
+            StackItem::Integer(ref n) => n.to_i64(),
+            _ => None,
+        }
+    }
+}
+
+impl<I> IntoStackItem<I> for i64 where I: FromPrimitive {
+    fn into_stack_item(self) -> vm::Result<StackItem<I>> {
+        I::from_i64(self).map(StackItem::Integer).ok_or(vm::Error::IntegerOverflow)
+    }
+}
+
+impl<I> FromStackItem<I> for f64 {
+    fn from_stack_item(item: &StackItem<I>) -> Option<f64> {
+        match *item {
+            StackItem::Float(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+impl<I> IntoStackItem<I> for f64 {
+    fn into_stack_item(self) -> vm::Result<StackItem<I>> {
+        Ok(StackItem::Float(self))
+    }
+}
+
+impl<I> FromStackItem<I> for String {
+    fn from_stack_item(item: &StackItem<I>) -> Option<String> {
+        match *item {
+            StackItem::String(ref s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<I> IntoStackItem<I> for String {
+    fn into_stack_item(self) -> vm::Result<StackItem<I>> {
+        Ok(StackItem::String(self))
+    }
+}
+
+impl<I> FromStackItem<I> for bool {
+    fn from_stack_item(item: &StackItem<I>) -> Option<bool> {
+        match *item {
+            StackItem::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl<I> IntoStackItem<I> for bool {
+    fn into_stack_item(self) -> vm::Result<StackItem<I>> {
+        Ok(StackItem::Boolean(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::rc::Rc;
+    use parse::parse;
+    use vm;
+    use super::{Block, BlockItem, Stack, StackItem};
+
+    #[test]
+    fn test_peek() {
+        let mut stack = Stack::<i64>::new();
+        assert_eq!(stack.peek(), Err(vm::Error::StackUnderflow));
+        assert_eq!(stack.peek_mut(), Err(vm::Error::StackUnderflow));
+        stack.push(StackItem::Integer(1)).unwrap();
+        stack.push(StackItem::Integer(2)).unwrap();
+        assert_eq!(stack.peek(), Ok(&StackItem::Integer(2)));
+        *stack.peek_mut().unwrap() = StackItem::Integer(3);
+        assert_eq!(stack.0, vec![StackItem::Integer(1), StackItem::Integer(3)]);
+    }
+
+    #[test]
+    fn test_pop_many() {
+        let mut stack = Stack::<i64>::new();
+        for n in 1..5 {
+            stack.push(StackItem::Integer(n)).unwrap();
+        }
+        assert_eq!(stack.pop2(), Ok((StackItem::Integer(3), StackItem::Integer(4))));
+        assert_eq!(stack.pop3(), Err(vm::Error::StackUnderflow));
+        assert_eq!(stack.0.len(), 2);
+        stack.push(StackItem::Integer(5)).unwrap();
+        assert_eq!(stack.pop3(), Ok((StackItem::Integer(1), StackItem::Integer(2),
+                                     StackItem::Integer(5))));
+        assert_eq!(stack.pop2(), Err(vm::Error::StackUnderflow));
+    }
+
+    #[test]
+    fn test_typed_pop() {
+        let mut stack = Stack::<i64>::new();
+        let block = Rc::new(Block(vec![]));
+        for item in [StackItem::Block(block.clone()),
+                         StackItem::Symbol("sym".to_string()),
+                         StackItem::Boolean(true),
+                         StackItem::String("s".to_string()),
+                         StackItem::Float(1.5),
+                         StackItem::Integer(1)] {
+            stack.push(item).unwrap();
+        }
+        assert_eq!(stack.pop_float(), Err(vm::Error::TypeError));
+        assert_eq!(stack.pop_integer(), Ok(1));
+        assert_eq!(stack.pop_string(), Err(vm::Error::TypeError));
+        assert_eq!(stack.pop_float(), Ok(1.5));
+        assert_eq!(stack.pop_bool(), Err(vm::Error::TypeError));
+        assert_eq!(stack.pop_string(), Ok("s".to_string()));
+        assert_eq!(stack.pop_symbol(), Err(vm::Error::TypeError));
+        assert_eq!(stack.pop_bool(), Ok(true));
+        assert_eq!(stack.pop_block(), Err(vm::Error::TypeError));
+        assert_eq!(stack.pop_symbol(), Ok("sym".to_string()));
+        assert_eq!(stack.pop_integer(), Err(vm::Error::TypeError));
+        assert_eq!(stack.pop_block(), Ok(block));
+        assert_eq!(stack.pop_integer(), Err(vm::Error::StackUnderflow));
+        stack.push(StackItem::Char('c')).unwrap();
+        assert_eq!(stack.pop_string(), Err(vm::Error::TypeError));
+        assert_eq!(stack.pop_char(), Ok('c'));
+    }
+
+    #[test]
+    fn test_approx_size() {
+        let base = mem::size_of::<StackItem<i64>>();
+        assert_eq!(StackItem::<i64>::Integer(1).approx_size(), base);
+        assert_eq!(StackItem::<i64>::String("abcd".into()).approx_size(), base + 4);
+        let small = StackItem::<i64>::Block(Rc::new(Block(vec![
+            BlockItem::Literal(StackItem::String("x".repeat(10)))])));
+        let large = StackItem::<i64>::Block(Rc::new(Block(vec![
+            BlockItem::Literal(StackItem::String("x".repeat(1000)))])));
+        assert_eq!(large.approx_size() - small.approx_size(), 990);
+        let nested = StackItem::Block(Rc::new(Block(vec![
+            BlockItem::Literal(large.clone()), BlockItem::Literal(large.clone())])));
+        assert!(nested.approx_size() > 2 * large.approx_size());
+    }
+
+    #[test]
+    fn test_stack_approx_size() {
+        let base = mem::size_of::<StackItem<i64>>();
+        let block = StackItem::<i64>::Block(Rc::new(Block(vec![
+            BlockItem::Literal(StackItem::String("x".repeat(1000)))])));
+        let mut stack = Stack::new();
+        stack.push(StackItem::String("abcd".into())).unwrap();
+        assert_eq!(stack.approx_size(), base + 4);
+        // A shared block counts once, until the last copy is popped.
+        for _ in 0..3 {
+            stack.push(block.clone()).unwrap();
+        }
+        assert_eq!(stack.approx_size(), base + 4 + block.approx_size() + 2 * base);
+        stack.truncate(2);
+        assert_eq!(stack.approx_size(), base + 4 + block.approx_size());
+        stack.replace(0, StackItem::Integer(1));
+        assert_eq!(stack.pop(), Ok(block));
+        assert_eq!(stack.approx_size(), base);
+        stack.0.push(StackItem::Integer(2));
+        stack.recount();
+        assert_eq!(stack.approx_size(), 2 * base);
+    }
+
+    #[test]
+    fn test_float_display_round_trips() {
+        let three = StackItem::<i64>::Float(3.0);
+        assert_eq!(three.to_string(), "3.0");
+        assert_eq!(parse(&three.to_string()),
+            Ok(Block(vec![BlockItem::Literal(three)])));
+        assert_eq!(StackItem::<i64>::Float(2.5).to_string(), "2.5");
+    }
+}
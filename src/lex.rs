@@ -18,6 +18,8 @@ pub enum Error {
     UnclosedComment,
     UnclosedString,
     MalformedNumber,
+    MalformedChar,
+    TokenTooLong,
 }
 
 impl Error {
@@ -47,31 +49,54 @@ impl error::Error for Error {
             Error::UnclosedComment => "Unclosed comment",
             Error::UnclosedString => "Unclosed string",
             Error::MalformedNumber => "Malformed integer or float",
+            Error::MalformedChar => "Malformed character",
+            Error::TokenTooLong => "Token too long",
         }
     }
 }
 
+/// A line and column in the source, both counted from 1.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
 /// Yield chars while allowing one char to be "replaced" to be yielded again.
 ///
 /// In the case that a char must be popped in order to determine
 /// if it belongs in the current token, replace can be used to
 /// remember it for the start of the next token. This works
 /// as long as the lexer grammar needs only one char of lookahead.
+///
+/// The position of the next char is tracked as well.
 struct ReplaceOneChars<'a> {
     chars: Chars<'a>,
     replaced: Option<char>,
+    position: Position,
+    last_position: Position,
 }
 
 impl<'a> ReplaceOneChars<'a> {
     pub fn new(chars: Chars<'a>) -> ReplaceOneChars<'a> {
+        let start = Position { line: 1, col: 1 };
         ReplaceOneChars {
             chars: chars,
             replaced: None,
+            position: start,
+            last_position: start,
         }
     }
 
     pub fn replace(&mut self, c: char) {
         self.replaced = Some(c);
+        self.position = self.last_position;
     }
 }
 
@@ -79,10 +104,17 @@ impl<'a> Iterator for ReplaceOneChars<'a> {
     type Item = char;
 
     fn next(&mut self) -> Option<char> {
-        match self.replaced.take() {
-            Some(c) => Some(c),
-            None => self.chars.next(),
+        let next = self.replaced.take().or_else(|| self.chars.next());
+        if let Some(c) = next {
+            self.last_position = self.position;
+            if c == '\n' {
+                self.position.line += 1;
+                self.position.col = 1;
+            } else {
+                self.position.col += 1;
+            }
         }
+        next
     }
 }
 
@@ -92,10 +124,13 @@ pub enum Token {
     Integer(String),
     Float(String),
     String(String),
+    Char(char),
     Symbol(String),
     Call(String),
     OpenBrace,
     CloseBrace,
+    OpenBracket,
+    CloseBracket,
     Whitespace,
     Comment,
 }
@@ -105,17 +140,51 @@ pub enum Token {
 /// or an error is encountered.
 pub struct Lexer<'a> {
     chars: ReplaceOneChars<'a>,
+    max_token_len: Option<usize>,
+    token_start: Position,
 }
 
 const DECIMAL: u32 = 10u32;
 
-const SPECIAL_CHARS: [char; 7] = ['#', '(', ')', '"', '{', '}', ':'];
+const SPECIAL_CHARS: [char; 10] = ['#', '(', ')', '"', '\'', '{', '}', '[', ']', ':'];
 
 impl<'a> Lexer<'a> {
     /// Create a new lexer over the provided source code.
     pub fn new(src: &'a str) -> Lexer<'a> {
         Lexer {
             chars: ReplaceOneChars::new(src.chars()),
+            max_token_len: None,
+            token_start: Position { line: 1, col: 1 },
+        }
+    }
+
+    /// Create a new lexer which rejects number, string and symbol tokens
+    /// longer than `max_token_len` bytes.
+    pub fn with_max_token_len(src: &'a str, max_token_len: usize) -> Lexer<'a> {
+        Lexer {
+            chars: ReplaceOneChars::new(src.chars()),
+            max_token_len: Some(max_token_len),
+            token_start: Position { line: 1, col: 1 },
+        }
+    }
+
+    /// Where the token most recently returned, or the error, began.
+    pub fn token_start(&self) -> Position {
+        self.token_start
+    }
+
+    /// Iterate over tokens other than whitespace and comments.
+    pub fn tokens(self) -> impl Iterator<Item = Result<Token>> + 'a {
+        self.filter(|token| {
+            *token != Ok(Token::Whitespace) && *token != Ok(Token::Comment)
+        })
+    }
+
+    /// Check a partially read token against the maximum token length.
+    fn check_len(&self, s: &str) -> Result<()> {
+        match self.max_token_len {
+            Some(max) if s.len() > max => Err(Error::TokenTooLong),
+            _ => Ok(()),
         }
     }
 
@@ -143,6 +212,7 @@ impl<'a> Lexer<'a> {
             match self.chars.next() {
                 Some(c) => if c.is_digit(DECIMAL) {
                     s.push(c);
+                    try!(self.check_len(&s));
                 } else if !is_float && c == '.' {
                     is_float = true;
                     s.push(c);
@@ -171,12 +241,19 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    /// Parenthesized comments nest, so `( a ( b ) c )` is one comment.
     fn multi_comment(&mut self) -> Result<Token> {
+        let mut depth = 1;
         loop {
             match self.chars.next() {
-                Some(c) => if c == ')' {
-                    return Ok(Token::Comment);
+                Some('(') => depth += 1,
+                Some(')') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(Token::Comment);
+                    }
                 },
+                Some(_) => continue,
                 None => return Err(Error::UnclosedComment),
             }
         }
@@ -186,6 +263,7 @@ impl<'a> Lexer<'a> {
         match self.chars.next() {
             Some(c) => return match c {
                 '"' => Ok('"'),
+                '\'' => Ok('\''),
                 'n' => Ok('\n'),
                 'r' => Ok('\r'),
                 't' => Ok('\t'),
@@ -195,13 +273,30 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn string(&mut self) -> Result<Token> {
+    /// Read the rest of a string after its opening quote. A `\r\n` line
+    /// ending inside the string is read as `\n`, so strings do not depend on
+    /// how the source file was saved.
+    fn string(&mut self) -> Result<String> {
+        self.quoted('"')
+    }
+
+    /// Read up to the closing `quote` as for a string, with the same escapes.
+    fn quoted(&mut self, quote: char) -> Result<String> {
         let mut s = String::new();
         loop {
+            try!(self.check_len(&s));
             match self.chars.next() {
                 Some(c) => match c {
                     '\\' => s.push(try!(self.escape())),
-                    '"' => return Ok(Token::String(s)),
+                    c if c == quote => return Ok(s),
+                    '\r' => match self.chars.next() {
+                        Some('\n') => s.push('\n'),
+                        Some(next) => {
+                            s.push('\r');
+                            self.chars.replace(next);
+                        },
+                        None => return Err(Error::UnclosedString),
+                    },
                     _ => s.push(c),
                 },
                 None => return Err(Error::UnclosedString),
@@ -209,6 +304,33 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    /// Read the rest of a char literal such as `'a'` or `'\n'` after its
+    /// opening quote.
+    fn char_literal(&mut self) -> Result<Token> {
+        let c = match self.chars.next() {
+            Some('\\') => try!(self.escape()),
+            Some('\'') | None => return Err(Error::MalformedChar),
+            Some(c) => c,
+        };
+        match self.chars.next() {
+            Some('\'') => Ok(Token::Char(c)),
+            _ => Err(Error::MalformedChar),
+        }
+    }
+
+    /// A symbol is either quoted like a string, e.g. `:"two words"`, or runs
+    /// up to the next whitespace or special char.
+    fn quoted_or_plain_symbol(&mut self) -> Result<Token> {
+        match self.chars.next() {
+            Some('"') => self.string().map(Token::Symbol),
+            Some(c) => {
+                self.chars.replace(c);
+                self.symbol().map(Token::Symbol)
+            },
+            None => Ok(Token::Symbol(String::new())),
+        }
+    }
+
     fn symbol(&mut self) -> Result<String> {
         let mut s = String::new();
         loop {
@@ -218,6 +340,7 @@ impl<'a> Lexer<'a> {
                     return Ok(s);
                 } else {
                     s.push(c);
+                    try!(self.check_len(&s));
                 },
                 None => return Ok(s),
             }
@@ -230,6 +353,7 @@ impl<'a> Iterator for Lexer<'a> {
 
     fn next(&mut self) -> Option<Result<Token>> {
         loop {
+            self.token_start = self.chars.position;
             let c = match self.chars.next() {
                 Some(c) => c,
                 None => return None,
@@ -244,13 +368,22 @@ impl<'a> Iterator for Lexer<'a> {
             } else if c == '(' {
                 self.multi_comment()
             } else if c == '"' {
-                self.string()
+                self.string().map(Token::String)
+            } else if c == '\'' {
+                self.char_literal()
             } else if c == '{' {
                 Ok(Token::OpenBrace)
             } else if c == '}' {
                 Ok(Token::CloseBrace)
+            } else if c == '[' {
+                Ok(Token::OpenBracket)
+            } else if c == ']' {
+                Ok(Token::CloseBracket)
             } else if c == ':' {
-                self.symbol().map(|s| Token::Symbol(s))
+                self.quoted_or_plain_symbol()
+            } else if c == '`' {
+                // A call quoted like a symbol, e.g. `two words`.
+                self.quoted('`').map(Token::Call)
             } else {
                 self.chars.replace(c);
                 self.symbol().map(|s| Token::Call(s))
@@ -266,7 +399,20 @@ impl<'a> Iterator for Lexer<'a> {
 
 #[cfg(test)]
 mod tests {
-    use super::{Lexer, Token, Error};
+    use super::{Lexer, Token, Error, Position};
+
+    #[test]
+    fn test_token_start() {
+        let mut lexer = Lexer::new("ab 12\n  {");
+        let mut starts = Vec::new();
+        while let Some(token) = lexer.next() {
+            if token != Ok(Token::Whitespace) {
+                starts.push(lexer.token_start());
+            }
+        }
+        assert_eq!(starts, vec![Position { line: 1, col: 1 }, Position { line: 1, col: 4 },
+                                Position { line: 2, col: 3 }]);
+    }
 
     #[test]
     fn test_empty_string_is_none() {
@@ -289,12 +435,52 @@ mod tests {
             vec![Err(Error::UnclosedString)]);
     }
 
+    #[test]
+    fn test_char() {
+        assert_eq!(Lexer::new("'A' '\\n' '\\''").tokens().collect::<Vec<_>>(),
+            vec![Ok(Token::Char('A')), Ok(Token::Char('\n')), Ok(Token::Char('\''))]);
+        assert_eq!(Lexer::new("'ab'").collect::<Vec<_>>(), vec![Err(Error::MalformedChar)]);
+        assert_eq!(Lexer::new("''").collect::<Vec<_>>(), vec![Err(Error::MalformedChar)]);
+    }
+
     #[test]
     fn test_symbol() {
         assert_eq!(Lexer::new(":this-is-a-symbol").collect::<Vec<_>>(),
             vec![Ok(Token::Symbol("this-is-a-symbol".into()))]);
     }
 
+    #[test]
+    fn test_quoted_symbol() {
+        assert_eq!(Lexer::new(":\"a b\"").collect::<Vec<_>>(),
+            vec![Ok(Token::Symbol("a b".into()))]);
+        assert_eq!(Lexer::new(":\"}\\n\"{").collect::<Vec<_>>(),
+            vec![Ok(Token::Symbol("}\n".into())), Ok(Token::OpenBrace)]);
+        assert_eq!(Lexer::new(":\"a").collect::<Vec<_>>(),
+            vec![Err(Error::UnclosedString)]);
+    }
+
+    #[test]
+    fn test_quoted_call() {
+        assert_eq!(Lexer::new("`a b`{").collect::<Vec<_>>(),
+            vec![Ok(Token::Call("a b".into())), Ok(Token::OpenBrace)]);
+        assert_eq!(Lexer::new("`a").collect::<Vec<_>>(),
+            vec![Err(Error::UnclosedString)]);
+    }
+
+    #[test]
+    fn test_nested_comment() {
+        assert_eq!(Lexer::new("( a ( b ) c )").collect::<Vec<_>>(),
+            vec![Ok(Token::Comment)]);
+        assert_eq!(Lexer::new("( a ( b ) c").collect::<Vec<_>>(),
+            vec![Err(Error::UnclosedComment)]);
+    }
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(Lexer::new("1 # c\n 2").tokens().collect::<Vec<_>>(),
+            vec![Ok(Token::Integer("1".into())), Ok(Token::Integer("2".into()))]);
+    }
+
     #[test]
     fn test_call() {
         assert_eq!(Lexer::new("this-is-a-call").collect::<Vec<_>>(),
@@ -309,6 +495,13 @@ mod tests {
             vec![Ok(Token::CloseBrace)]);
     }
 
+    #[test]
+    fn test_brackets() {
+        assert_eq!(Lexer::new("[a]").collect::<Vec<_>>(),
+            vec![Ok(Token::OpenBracket), Ok(Token::Call("a".into())),
+                 Ok(Token::CloseBracket)]);
+    }
+
     #[test]
     fn test_whitespace() {
         assert_eq!(Lexer::new(" ").collect::<Vec<_>>(),
@@ -325,4 +518,31 @@ mod tests {
                    .collect::<Vec<_>>(),
             vec![Err(Error::UnclosedComment)]);
     }
+
+    #[test]
+    fn test_crlf() {
+        assert_eq!(Lexer::new("1 # one\r\n2\r\n").tokens().collect::<Vec<_>>(),
+            vec![Ok(Token::Integer("1".into())), Ok(Token::Integer("2".into()))]);
+        assert_eq!(Lexer::new(":a\r\nb\r\n").tokens().collect::<Vec<_>>(),
+            vec![Ok(Token::Symbol("a".into())), Ok(Token::Call("b".into()))]);
+        assert_eq!(Lexer::new("\"a\r\nb\"").collect::<Vec<_>>(),
+            vec![Ok(Token::String("a\nb".into()))]);
+        assert_eq!(Lexer::new("\"a\rb\\r\"").collect::<Vec<_>>(),
+            vec![Ok(Token::String("a\rb\r".into()))]);
+        assert_eq!(Lexer::new("(a\r\nb)\r\n").collect::<Vec<_>>(),
+            vec![Ok(Token::Comment), Ok(Token::Whitespace)]);
+    }
+
+    #[test]
+    fn test_max_token_len() {
+        assert_eq!(Lexer::with_max_token_len(":short", 8).collect::<Vec<_>>(),
+            vec![Ok(Token::Symbol("short".into()))]);
+        assert_eq!(Lexer::with_max_token_len(":much-too-long", 8).collect::<Vec<_>>(),
+            vec![Err(Error::TokenTooLong)]);
+        assert_eq!(Lexer::with_max_token_len("\"much too long\"", 8)
+                   .collect::<Vec<_>>(),
+            vec![Err(Error::TokenTooLong)]);
+        assert_eq!(Lexer::with_max_token_len("1234567890", 8).collect::<Vec<_>>(),
+            vec![Err(Error::TokenTooLong)]);
+    }
 }
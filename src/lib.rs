@@ -4,7 +4,7 @@
 
 extern crate num;
 
-mod lex;
+pub mod lex;
 pub mod item;
 pub mod parse;
 pub mod vm;
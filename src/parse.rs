@@ -3,10 +3,12 @@
 use std::result::Result::{Ok, Err};
 use std::{error, result};
 use std::fmt;
-use std::convert::From;
 use std::str::FromStr;
+use std::rc::Rc;
+use std::marker::PhantomData;
 use item::{Block, BlockItem, StackItem};
 use lex::{self, Token};
+pub use lex::Position;
 use std::error::Error as StdError;
 
 /// Result of a parser operation.
@@ -15,15 +17,37 @@ pub type Result<I> = result::Result<Block<I>, Error>;
 /// Possible error due to parser operation.
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Error {
-    UnclosedBlock,
-    LexError(lex::Error),
+    /// A block opened at the given position was never closed.
+    UnclosedBlock(Position),
+    /// A list opened at the given position was never closed.
+    UnclosedList(Position),
+    /// A `}` or `]` at the given position closing the other kind of bracket.
+    MismatchedBracket(Position),
+    /// A `}` at the given position with no block to close.
+    UnexpectedCloseBrace(Position),
+    /// An integer literal at the given position too large for the integer
+    /// type.
+    IntegerLiteralOverflow(Position),
+    /// A lexer error in the token starting at the given position.
+    LexError(lex::Error, Position),
 }
 
 impl Error {
     pub fn is_recoverable(&self) -> bool {
         match *self {
-            Error::UnclosedBlock => true,
-            Error::LexError(e) => e.is_recoverable(),
+            Error::UnclosedBlock(_) | Error::UnclosedList(_) => true,
+            Error::MismatchedBracket(_) | Error::UnexpectedCloseBrace(_)
+                | Error::IntegerLiteralOverflow(_) => false,
+            Error::LexError(e, _) => e.is_recoverable(),
+        }
+    }
+
+    /// Where in the source the error was found.
+    pub fn position(&self) -> Position {
+        match *self {
+            Error::UnclosedBlock(p) | Error::UnclosedList(p)
+                | Error::MismatchedBracket(p) | Error::UnexpectedCloseBrace(p)
+                | Error::IntegerLiteralOverflow(p) | Error::LexError(_, p) => p,
         }
     }
 }
@@ -37,63 +61,107 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
-            Error::UnclosedBlock => "Unclosed block",
+            Error::UnclosedBlock(_) => "Unclosed block",
+            Error::UnclosedList(_) => "Unclosed list",
+            Error::MismatchedBracket(_) => "Mismatched bracket",
+            Error::UnexpectedCloseBrace(_) => "Unexpected close brace",
+            Error::IntegerLiteralOverflow(_) => "Integer literal out of range",
             Error::LexError(..) => "Lexer error",
         }
     }
-}
 
-impl From<lex::Error> for Error {
-    fn from(err: lex::Error) -> Error {
-        Error::LexError(err)
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::LexError(ref e, _) => Some(e),
+            _ => None,
+        }
     }
 }
 
 // To reduce the burden on the programmer we just assume the top level source
 // is wrapped in an implicit block. This means we have two "classes" of blocks,
-// at least as far as the parser is concerned.
+// at least as far as the parser is concerned. Nested blocks and lists carry
+// the position of their opening bracket for error reporting.
 enum BlockLevel {
     Top,
-    Nested,
+    Nested(Position),
+    List(Position),
 }
 
-// Recursive parsing function; could be called just "parse" but we use that
-// for the public helper function which creates a lexer and creates the
-// top block.
-fn parse_block<I>(lexer: &mut lex::Lexer, block_level: BlockLevel) -> Result<I> 
+// Parse the next item of a block, skipping whitespace and comments. Returns
+// `None` once the block is closed, or at the end of the source for the top
+// level.
+fn next_item<I>(lexer: &mut lex::Lexer, block_level: &BlockLevel)
+        -> result::Result<Option<BlockItem<I>>, Error>
         where I: FromStr {
-    let mut block = Vec::new();
     loop {
         let token = match lexer.next() {
-            None => match block_level {
-                BlockLevel::Top => break,
-                BlockLevel::Nested => return Err(Error::UnclosedBlock),
+            None => return match *block_level {
+                BlockLevel::Top => Ok(None),
+                BlockLevel::Nested(start) => Err(Error::UnclosedBlock(start)),
+                BlockLevel::List(start) => Err(Error::UnclosedList(start)),
             },
-            Some(t) => try!(t),
+            Some(t) => try!(t.map_err(|e| Error::LexError(e, lexer.token_start()))),
         };
-        match token {
+        let item = match token {
             Token::Integer(s) => {
-                let i = s.parse().ok()
-                    .expect("lexer should have rejected integer");
-                block.push(BlockItem::Literal(StackItem::Integer(i)));
+                // The lexer only produces digits, so the only way this can
+                // fail is if the value does not fit the integer type.
+                let i = try!(s.parse()
+                    .map_err(|_| Error::IntegerLiteralOverflow(lexer.token_start())));
+                BlockItem::Literal(StackItem::Integer(i))
             },
             Token::Float(s) => {
                 let f = s.parse().ok()
                     .expect("lexer should have rejected float");
-                block.push(BlockItem::Literal(StackItem::Float(f)));
+                BlockItem::Literal(StackItem::Float(f))
             },
-            Token::String(s) =>
-                block.push(BlockItem::Literal(StackItem::String(s))),
-            Token::Symbol(s) => block.push(BlockItem::Literal(StackItem::Symbol(s))),
-            Token::Call(s) => block.push(BlockItem::Call(s)),
+            Token::String(s) => BlockItem::Literal(StackItem::String(s)),
+            Token::Char(c) => BlockItem::Literal(StackItem::Char(c)),
+            Token::Symbol(s) => BlockItem::Literal(StackItem::Symbol(s)),
+            Token::Call(s) => BlockItem::Call(s),
             Token::OpenBrace => {
-                let nested_block = try!(parse_block(lexer, BlockLevel::Nested));
-                block.push(BlockItem::Literal(StackItem::Block(nested_block)));
+                let start = BlockLevel::Nested(lexer.token_start());
+                let nested_block = try!(parse_block(lexer, start));
+                BlockItem::Literal(StackItem::Block(Rc::new(nested_block)))
             },
-            Token::CloseBrace => break,
-            Token::Whitespace | Token::Comment => (),
-        }
+            Token::OpenBracket => {
+                let start = BlockLevel::List(lexer.token_start());
+                let list = try!(parse_block(lexer, start));
+                BlockItem::List(list)
+            },
+            Token::CloseBrace => return match *block_level {
+                BlockLevel::Top => Err(Error::UnexpectedCloseBrace(lexer.token_start())),
+                BlockLevel::Nested(_) => Ok(None),
+                BlockLevel::List(_) => Err(Error::MismatchedBracket(lexer.token_start())),
+            },
+            Token::CloseBracket => return match *block_level {
+                BlockLevel::List(_) => Ok(None),
+                _ => Err(Error::MismatchedBracket(lexer.token_start())),
+            },
+            Token::Whitespace | Token::Comment => continue,
+        };
+        return Ok(Some(item));
     }
+}
+
+// Recursive parsing function; could be called just "parse" but we use that
+// for the public helper function which creates a lexer and creates the
+// top block. Items are collected into `block` as they are parsed, so a caller
+// can keep whatever was read before an error.
+fn parse_items<I>(lexer: &mut lex::Lexer, block_level: BlockLevel,
+                  block: &mut Vec<BlockItem<I>>) -> result::Result<(), Error>
+        where I: FromStr {
+    while let Some(item) = try!(next_item(lexer, &block_level)) {
+        block.push(item);
+    }
+    Ok(())
+}
+
+fn parse_block<I>(lexer: &mut lex::Lexer, block_level: BlockLevel) -> Result<I>
+        where I: FromStr {
+    let mut block = Vec::new();
+    try!(parse_items(lexer, block_level, &mut block));
     Ok(Block(block))
 }
 
@@ -104,19 +172,150 @@ pub fn parse<I>(src: &str) -> Result<I>
     parse_block(&mut lexer, BlockLevel::Top)
 }
 
+/// Parse as much of a source string as possible.
+///
+/// Unlike `parse`, the top-level items read before an error are kept and
+/// returned along with the error, which suits source that is still being
+/// edited. Items inside an unfinished nested block are not included.
+pub fn parse_recoverable<I>(src: &str) -> (Block<I>, Option<Error>)
+        where I: FromStr {
+    let mut lexer = lex::Lexer::new(src);
+    let mut block = Vec::new();
+    let err = parse_items(&mut lexer, BlockLevel::Top, &mut block).err();
+    (Block(block), err)
+}
+
+/// Attempt to parse a source string, rejecting any number, string or symbol
+/// longer than `max_token_len` bytes.
+pub fn parse_with_max_token_len<I>(src: &str, max_token_len: usize) -> Result<I>
+        where I: FromStr {
+    let mut lexer = lex::Lexer::with_max_token_len(src, max_token_len);
+    parse_block(&mut lexer, BlockLevel::Top)
+}
+
+/// Iterator over the top-level items of a source string, parsing each only
+/// when it is asked for. Nested blocks and lists are parsed whole. Iteration
+/// stops after the first error.
+pub struct ParseStream<'a, I> {
+    lexer: lex::Lexer<'a>,
+    done: bool,
+    marker: PhantomData<I>,
+}
+
+impl<'a, I> Iterator for ParseStream<'a, I> where I: FromStr {
+    type Item = result::Result<BlockItem<I>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match next_item(&mut self.lexer, &BlockLevel::Top) {
+            Ok(Some(item)) => Some(Ok(item)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+/// Parse a source string lazily, one top-level item at a time.
+pub fn parse_stream<'a, I>(src: &'a str) -> ParseStream<'a, I>
+        where I: FromStr {
+    ParseStream {
+        lexer: lex::Lexer::new(src),
+        done: false,
+        marker: PhantomData,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Error, parse};
+    use std::rc::Rc;
+    use std::error::Error as StdError;
+    use super::{Error, Position, parse, parse_recoverable, parse_stream};
+    use lex;
     use item::{Block, BlockItem, StackItem};
 
     #[test]
     fn test_all_simple() {
         assert_eq!(parse(r#"(comment) {} "string" 1 1.0 call :symbol"#),
-            Ok(Block(vec![BlockItem::Literal(StackItem::Block(Block(vec![]))),
+            Ok(Block(vec![BlockItem::Literal(StackItem::Block(Rc::new(Block(vec![])))),
                     BlockItem::Literal(StackItem::String("string".to_string())),
                     BlockItem::Literal(StackItem::Integer(1)),
                     BlockItem::Literal(StackItem::Float(1.0)),
                     BlockItem::Call("call".to_string()),
                     BlockItem::Literal(StackItem::Symbol("symbol".to_string()))])));
     }
+
+    #[test]
+    fn test_parse_recoverable() {
+        assert_eq!(parse_recoverable("1 2 {"),
+            (Block(vec![BlockItem::Literal(StackItem::Integer(1)),
+                        BlockItem::Literal(StackItem::Integer(2))]),
+             Some(Error::UnclosedBlock(Position { line: 1, col: 5 }))));
+        assert_eq!(parse_recoverable::<i64>("1 { 2 }"), (parse("1 { 2 }").unwrap(), None));
+    }
+
+    #[test]
+    fn test_list() {
+        assert_eq!(parse("[1 { }]"),
+            Ok(Block(vec![BlockItem::List(Block(vec![
+                BlockItem::Literal(StackItem::Integer(1)),
+                BlockItem::Literal(StackItem::Block(Rc::new(Block(vec![]))))]))])));
+        assert_eq!(parse::<i64>("[1 2"), Err(Error::UnclosedList(Position { line: 1, col: 1 })));
+        assert_eq!(parse::<i64>("[1 2 }"),
+                   Err(Error::MismatchedBracket(Position { line: 1, col: 6 })));
+        assert_eq!(parse::<i64>("{ 1\n ]"),
+                   Err(Error::MismatchedBracket(Position { line: 2, col: 2 })));
+    }
+
+    #[test]
+    fn test_integer_literal_overflow() {
+        assert_eq!(parse::<i8>("99"),
+            Ok(Block(vec![BlockItem::Literal(StackItem::Integer(99))])));
+        assert_eq!(parse::<i8>("1 99999"),
+                   Err(Error::IntegerLiteralOverflow(Position { line: 1, col: 3 })));
+    }
+
+    #[test]
+    fn test_error_position() {
+        assert_eq!(parse::<i64>("1\n{ 2\n  { 3 }\n"),
+                   Err(Error::UnclosedBlock(Position { line: 2, col: 1 })));
+        assert_eq!(parse::<i64>("1 2\n  \"abc"),
+                   Err(Error::LexError(lex::Error::UnclosedString,
+                                       Position { line: 2, col: 3 })));
+    }
+
+    #[test]
+    fn test_unexpected_close_brace() {
+        assert_eq!(parse::<i64>("1 } 2"),
+                   Err(Error::UnexpectedCloseBrace(Position { line: 1, col: 3 })));
+        assert!(parse::<i64>("1 { 2 } 3").is_ok());
+    }
+
+    #[test]
+    fn test_error_source() {
+        let err = parse::<i64>("\"abc").unwrap_err();
+        assert_eq!(err.source().map(|e| e.to_string()),
+                   Some(lex::Error::UnclosedString.to_string()));
+        assert!(Error::MismatchedBracket(Position { line: 1, col: 1 }).source().is_none());
+    }
+
+    #[test]
+    fn test_parse_stream() {
+        let src = "1 { 2 3 } [4] call";
+        let mut stream = parse_stream::<i64>(src);
+        assert_eq!(stream.next(), Some(Ok(BlockItem::Literal(StackItem::Integer(1)))));
+        let rest: Vec<_> = stream.map(|item| item.unwrap()).collect();
+        let mut items = parse::<i64>(src).unwrap().0;
+        assert_eq!(items.split_off(1), rest);
+        let errors: Vec<_> = parse_stream::<i64>("1 ] 2").collect();
+        assert_eq!(errors, vec![Ok(BlockItem::Literal(StackItem::Integer(1))),
+                                Err(Error::MismatchedBracket(Position { line: 1, col: 3 }))]);
+    }
 }
@@ -1,11 +1,15 @@
 //! Virtual machine.
 
 use std::rc::Rc;
-use std::{error, result};
+use std::cell::RefCell;
+use std::{error, io, result};
+use std::io::{BufRead, Write};
 use std::fmt;
-use std::collections::HashMap;
+use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
 use std::error::Error as StdError;
-use item::{Block, BlockItem, Stack};
+use item::{Block, BlockItem, Stack, StackItem, FromStackItem, IntoStackItem};
+use parse::parse;
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -17,18 +21,74 @@ pub enum Error {
     NumericConversion,
     DivideByZero,
     StackUnderflow,
+    MemoryLimit,
+    Io(String),
     UnknownMethod(String),
+    ParseError(String),
+    RecursionLimit,
+    Protected(String),
+    StackOverflow,
+    RedefineBuiltin(String),
+    AssertionFailed(String),
+    /// Raised by `quit` to stop the program; not caught by `try`.
+    Quit,
+    /// The named method changed the stack depth other than as declared.
+    StackEffectMismatch(String),
+    /// The stack held a different number of items than expected.
+    UnexpectedDepth { expected: usize, found: usize },
+    /// The named builtin with mutable state was called while already running.
+    ReentrantCall(String),
+}
+
+impl Error {
+    /// Short machine-readable name of the error, as reported by `error-kind`.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Error::TypeError => "type-error",
+            Error::OutOfBounds => "out-of-bounds",
+            Error::IntegerOverflow => "integer-overflow",
+            Error::NumericConversion => "numeric-conversion",
+            Error::DivideByZero => "divide-by-zero",
+            Error::StackUnderflow => "stack-underflow",
+            Error::MemoryLimit => "memory-limit",
+            Error::Io(_) => "io",
+            Error::UnknownMethod(_) => "unknown-method",
+            Error::ParseError(_) => "parse-error",
+            Error::RecursionLimit => "recursion-limit",
+            Error::Protected(_) => "protected",
+            Error::StackOverflow => "stack-overflow",
+            Error::RedefineBuiltin(_) => "redefine-builtin",
+            Error::AssertionFailed(_) => "assertion-failed",
+            Error::Quit => "quit",
+            Error::StackEffectMismatch(_) => "stack-effect-mismatch",
+            Error::UnexpectedDepth { .. } => "unexpected-depth",
+            Error::ReentrantCall(_) => "reentrant-call",
+        }
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.description()));
         match *self {
-            Error::UnknownMethod(ref s) => write!(f, "{}: {}", self.description(), s),
-            _ => write!(f, "{}", self.description()),
+            Error::UnknownMethod(ref s) | Error::Io(ref s) |
+            Error::ParseError(ref s) | Error::Protected(ref s) |
+            Error::RedefineBuiltin(ref s) | Error::AssertionFailed(ref s) |
+            Error::StackEffectMismatch(ref s) | Error::ReentrantCall(ref s) =>
+                write!(f, ": {}", s),
+            Error::UnexpectedDepth { expected, found } =>
+                write!(f, ": expected {}, found {}", expected, found),
+            _ => Ok(()),
         }
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err.to_string())
+    }
+}
+
 impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
@@ -38,58 +98,936 @@ impl error::Error for Error {
             Error::NumericConversion => "Unable to interconvert numeric types",
             Error::TypeError => "Type error",
             Error::StackUnderflow => "Stack underflow",
+            Error::MemoryLimit => "Memory limit exceeded",
+            Error::Io(_) => "I/O error",
             Error::UnknownMethod(_) => "Unknown method",
+            Error::ParseError(_) => "Parse error",
+            Error::RecursionLimit => "Recursion limit exceeded",
+            Error::Protected(_) => "Method is protected",
+            Error::StackOverflow => "Stack overflow",
+            Error::RedefineBuiltin(_) => "Cannot redefine builtin",
+            Error::AssertionFailed(_) => "Assertion failed",
+            Error::Quit => "Quit requested",
+            Error::StackEffectMismatch(_) => "Stack effect mismatch",
+            Error::UnexpectedDepth { .. } => "Unexpected stack depth",
+            Error::ReentrantCall(_) => "Builtin called while already running",
         }
     }
 }
 
+/// The number of items a method takes and leaves, as declared by a stack
+/// effect such as `( a b -- c )`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct StackEffect {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl StackEffect {
+    /// Parse a stack effect, which must be in parentheses with `--` between
+    /// the inputs and outputs. `None` if it is malformed or uses `...`, since
+    /// then the counts are not fixed.
+    pub fn parse(src: &str) -> Option<StackEffect> {
+        let src = src.trim();
+        if !src.starts_with('(') || !src.ends_with(')') {
+            return None;
+        }
+        let names: Vec<_> = src[1..src.len() - 1].split_whitespace().collect();
+        if names.contains(&"...") {
+            return None;
+        }
+        let split = names.iter().position(|name| *name == "--")?;
+        if names[split + 1..].contains(&"--") {
+            return None;
+        }
+        Some(StackEffect { inputs: split, outputs: names.len() - split - 1 })
+    }
+
+    // Whether going from `before` to `after` items matches this effect.
+    fn allows(&self, before: usize, after: usize) -> bool {
+        before >= self.inputs && after + self.inputs == before + self.outputs
+    }
+}
+
+/// A builtin, see `Vm::insert_builtin`.
+pub type BuiltinFn<I> = Box<dyn Fn(&mut Vm<I>) -> Result<()>>;
+
+/// A builtin with mutable state, see `Vm::insert_builtin_mut`.
+pub type BuiltinMut<I> = Box<dyn FnMut(&mut Vm<I>) -> Result<()>>;
+
 pub enum Method<I> {
-    Builtin(Box<Fn(&mut Vm<I>) -> Result<()>>),
-    Block(Block<I>),
+    Builtin(BuiltinFn<I>),
+    /// A builtin with its own mutable state. It cannot call itself, even
+    /// indirectly; doing so fails with `ReentrantCall`.
+    BuiltinMut(RefCell<BuiltinMut<I>>),
+    Block(Rc<Block<I>>),
+    /// A block which must change the stack as its declared effect says, or
+    /// fail with `StackEffectMismatch`.
+    CheckedBlock(Rc<Block<I>>, StackEffect),
+}
+
+/// A block whose calls have been resolved by `Vm::link`.
+pub struct LinkedBlock<I>(pub Vec<LinkedItem<I>>);
+
+pub enum LinkedItem<I> {
+    /// An item run as usual, including calls that could not be resolved.
+    Item(BlockItem<I>),
+    Call(String, Rc<Method<I>>),
+    List(LinkedBlock<I>),
+}
+
+// A block literal resolved by `Vm::link`, kept alive so that its address,
+// by which it is looked up, stays valid.
+type LinkedLiteral<I> = (Rc<Block<I>>, Rc<LinkedBlock<I>>);
+
+// A block being run by `Vm::run_iterative`, and the method it belongs to.
+struct Frame<I> {
+    block: Rc<Block<I>>,
+    index: usize,
+    method: Option<String>,
+}
+
+/// Callback run around method calls, given the method name and the stack.
+pub type CallHook<I> = Box<dyn Fn(&str, &Stack<I>)>;
+
+// Output sink which can still be read after being handed to a `Vm`.
+#[derive(Clone)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 pub struct Vm<I> {
+    /// Embedders reading results should prefer `stack_items`.
     pub stack: Stack<I>,
     pub methods: HashMap<String, Rc<Method<I>>>,
+    /// Names of methods which scripts may no longer redefine.
+    pub protected: HashSet<String>,
+    /// Budget for the approximate size of the stack, in bytes; checked after
+    /// each item is run.
+    pub memory_limit: Option<usize>,
+    /// How deeply blocks may nest while running, counting both method calls
+    /// and control flow such as `if` and `while`.
+    pub max_depth: Option<usize>,
+    /// Whether arithmetic on an integer and a float converts the integer to
+    /// a float rather than failing with a type error. Off by default.
+    pub auto_promote: bool,
+    /// Run programs without touching anything outside the VM, such as output,
+    /// input or files, e.g. to check that every method called along the way
+    /// exists. The program still runs, so loops still loop and the stack and
+    /// methods change as usual.
+    pub dry_run: bool,
+    /// Called before each method call.
+    pub before_call: Option<CallHook<I>>,
+    /// Called after each method call returns, whether or not it succeeded.
+    pub after_call: Option<CallHook<I>>,
+    /// The most recent error caught by `try`.
+    pub last_error: Option<Error>,
+    /// The exit status asked for by `quit` or `exit`, once either has run.
+    pub quit_requested: Option<i32>,
+    /// Where builtins such as `print` write; standard output by default.
+    pub output: Box<dyn Write>,
+    /// Where builtins such as `read-line` read; standard input by default.
+    pub reader: Box<dyn BufRead>,
+    signatures: HashMap<String, String>,
+    // Methods as defined by `insert_builtin`, restored by `reset`.
+    builtins: HashMap<String, Rc<Method<I>>>,
+    depth: usize,
+    captured: Option<Capture>,
+    // Set while `run_iterative` is calling a builtin directly, in which case
+    // `run_block_tail` leaves its block in `tail` to be run as a new frame.
+    allow_tail: bool,
+    tail: Option<Rc<Block<I>>>,
+    // Block literals resolved by `link`, by address.
+    linked: HashMap<*const Block<I>, LinkedLiteral<I>>,
+    // Locals bound by `locals`, one scope for each method being run.
+    scopes: Vec<HashMap<String, StackItem<I>>>,
 }
 
 
 impl<I> Vm<I> where I: Clone {
     pub fn new() -> Vm<I> {
         Vm {
-            stack: Stack(Vec::new()),
+            stack: Stack::new(),
             methods: HashMap::new(),
+            protected: HashSet::new(),
+            memory_limit: None,
+            max_depth: None,
+            auto_promote: false,
+            dry_run: false,
+            before_call: None,
+            after_call: None,
+            last_error: None,
+            quit_requested: None,
+            output: Box::new(io::stdout()),
+            reader: Box::new(io::BufReader::new(io::stdin())),
+            signatures: HashMap::new(),
+            builtins: HashMap::new(),
+            depth: 0,
+            captured: None,
+            allow_tail: false,
+            tail: None,
+            linked: HashMap::new(),
+            scopes: Vec::new(),
         }
     }
 
     pub fn run(&mut self, item: &BlockItem<I>) -> Result<()> {
         match *item {
             BlockItem::Literal(ref stack_item) =>
-                self.stack.push(stack_item.clone()),
-            BlockItem::Call(ref name) => {
-                let method = match self.methods.get(&*name) {
-                    Some(m) => m.clone(),
-                    None => return Err(Error::UnknownMethod(name.clone())),
-                };
-                try!(match *method {
-                    Method::Builtin(ref f) => (**f)(self),
-                    Method::Block(ref b) => self.run_block(b),
+                try!(self.stack.push(stack_item.clone())),
+            BlockItem::Call(ref name) => try!(self.call_named(name)),
+            BlockItem::List(ref block) => {
+                let depth = self.stack.0.len();
+                try!(self.run_block(block));
+                try!(self.collect_list(depth));
+            },
+        }
+        self.check_memory_limit()
+    }
+
+    pub fn run_block(&mut self, block: &Block<I>) -> Result<()> {
+        if !self.linked.is_empty() {
+            let linked = self.linked.get(&(block as *const _)).map(|entry| entry.1.clone());
+            if let Some(linked) = linked {
+                return self.run_linked(&linked);
+            }
+        }
+        self.nested(|vm| {
+            for item in block.0.iter() {
+                try!(vm.run(item));
+            }
+            Ok(())
+        })
+    }
+
+    /// Run a block as the last thing a builtin does. Under `run_iterative`
+    /// this is done once the builtin returns, without recursing; otherwise
+    /// it is the same as `run_block`.
+    pub fn run_block_tail(&mut self, block: Rc<Block<I>>) -> Result<()> {
+        if self.allow_tail {
+            self.allow_tail = false;
+            self.tail = Some(block);
+            Ok(())
+        } else {
+            self.run_block(&block)
+        }
+    }
+
+    /// Run a block using an explicit stack of frames rather than recursion,
+    /// so that deeply recursive methods do not overflow the native stack.
+    ///
+    /// Calls to methods defined by blocks and the blocks run by builtins via
+    /// `run_block_tail`, such as `if` and `call`, get a new frame. Builtins
+    /// which loop, such as `times`, and list literals still recurse.
+    pub fn run_iterative(&mut self, block: Rc<Block<I>>) -> Result<()> {
+        let mut frames = vec![Frame { block, index: 0, method: None }];
+        let result = self.run_frames(&mut frames);
+        if result.is_err() {
+            self.allow_tail = false;
+            self.tail = None;
+            while let Some(frame) = frames.pop() {
+                self.end_frame(frame);
+            }
+        }
+        result
+    }
+
+    fn run_frames(&mut self, frames: &mut Vec<Frame<I>>) -> Result<()> {
+        loop {
+            let (block, index) = match frames.last_mut() {
+                Some(frame) => {
+                    frame.index += 1;
+                    (frame.block.clone(), frame.index - 1)
+                },
+                None => return Ok(()),
+            };
+            let name = match block.0.get(index) {
+                Some(BlockItem::Call(name)) => match self.local(name) {
+                    Some(item) => {
+                        try!(self.stack.push(item.clone()));
+                        continue;
+                    },
+                    None => name,
+                },
+                Some(item) => {
+                    try!(self.run(item));
+                    continue;
+                },
+                None => {
+                    let frame = frames.pop().unwrap();
+                    self.end_frame(frame);
+                    continue;
+                },
+            };
+            let method = match self.methods.get(name) {
+                Some(m) => m.clone(),
+                None => return Err(Error::UnknownMethod(name.clone())),
+            };
+            let next = if let Method::Block(ref b) = *method {
+                if let Some(ref hook) = self.before_call {
+                    hook(name, &self.stack);
+                }
+                self.scopes.push(HashMap::new());
+                Frame { block: b.clone(), index: 0, method: Some(name.clone()) }
+            } else {
+                self.allow_tail = true;
+                let result = self.call(name, &method);
+                self.allow_tail = false;
+                let tail = self.tail.take();
+                try!(result);
+                try!(self.check_memory_limit());
+                match tail {
+                    Some(b) => Frame { block: b, index: 0, method: None },
+                    None => continue,
+                }
+            };
+            if let Some(max) = self.max_depth {
+                if self.depth + frames.len() >= max {
+                    return Err(Error::RecursionLimit);
+                }
+            }
+            frames.push(next);
+        }
+    }
+
+    fn end_frame(&mut self, frame: Frame<I>) {
+        if let Some(ref name) = frame.method {
+            self.scopes.pop();
+            if let Some(ref hook) = self.after_call {
+                hook(name, &self.stack);
+            }
+        }
+    }
+
+    /// Resolve the calls in `block` to the methods currently defined, so
+    /// running it with `run_linked` needs no lookups by name. Calls to methods
+    /// not defined yet are left to be looked up when they run.
+    ///
+    /// Block literals inside are linked too, and are run linked whenever they
+    /// are run, e.g. as the body of `times` or a method, until `reset`.
+    pub fn link(&mut self, block: &Block<I>) -> LinkedBlock<I> {
+        let mut linked = Vec::with_capacity(block.0.len());
+        for item in &block.0 {
+            linked.push(match *item {
+                BlockItem::Call(ref name) => match self.methods.get(name) {
+                    Some(m) => LinkedItem::Call(name.clone(), m.clone()),
+                    None => LinkedItem::Item(item.clone()),
+                },
+                BlockItem::List(ref block) => LinkedItem::List(self.link(block)),
+                BlockItem::Literal(StackItem::Block(ref b)) => {
+                    let nested = Rc::new(self.link(b));
+                    self.linked.insert(&**b as *const _, (b.clone(), nested));
+                    LinkedItem::Item(item.clone())
+                },
+                _ => LinkedItem::Item(item.clone()),
+            });
+        }
+        LinkedBlock(linked)
+    }
+
+    pub fn run_linked(&mut self, block: &LinkedBlock<I>) -> Result<()> {
+        self.nested(|vm| {
+            for item in block.0.iter() {
+                match *item {
+                    LinkedItem::Item(ref item) => try!(vm.run(item)),
+                    LinkedItem::Call(ref name, ref method) => {
+                        match vm.local(name).cloned() {
+                            Some(item) => try!(vm.stack.push(item)),
+                            None => try!(vm.call(name, method)),
+                        }
+                        try!(vm.check_memory_limit());
+                    },
+                    LinkedItem::List(ref block) => {
+                        let depth = vm.stack.0.len();
+                        try!(vm.run_linked(block));
+                        try!(vm.collect_list(depth));
+                        try!(vm.check_memory_limit());
+                    },
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Look up a method by name and call it, as for a `Call` item.
+    pub fn call_named(&mut self, name: &str) -> Result<()> {
+        if let Some(item) = self.local(name).cloned() {
+            return self.stack.push(item);
+        }
+        let method = match self.methods.get(name) {
+            Some(m) => m.clone(),
+            None => return Err(Error::UnknownMethod(name.to_string())),
+        };
+        self.call(name, &method)
+    }
+
+    fn call(&mut self, name: &str, method: &Method<I>) -> Result<()> {
+        if let Some(ref hook) = self.before_call {
+            hook(name, &self.stack);
+        }
+        let result = match *method {
+            Method::Builtin(ref f) => (**f)(self),
+            Method::BuiltinMut(ref f) => match f.try_borrow_mut() {
+                Ok(mut f) => (*f)(self),
+                Err(_) => Err(Error::ReentrantCall(name.to_string())),
+            },
+            Method::Block(ref b) => self.run_scoped(b),
+            Method::CheckedBlock(ref b, effect) => {
+                let before = self.stack.0.len();
+                self.run_scoped(b).and_then(|()| {
+                    if effect.allows(before, self.stack.0.len()) {
+                        Ok(())
+                    } else {
+                        Err(Error::StackEffectMismatch(name.to_string()))
+                    }
                 })
             },
+        };
+        if let Some(ref hook) = self.after_call {
+            hook(name, &self.stack);
+        }
+        result
+    }
+
+    // Run the block of a method with its own scope for locals.
+    fn run_scoped(&mut self, block: &Block<I>) -> Result<()> {
+        self.scopes.push(HashMap::new());
+        let result = self.run_block(block);
+        self.scopes.pop();
+        result
+    }
+
+    /// Bind a local in the method being run, shadowing any method of the
+    /// same name until it returns. Outside any method the local lasts until
+    /// `reset`.
+    pub fn bind_local(&mut self, name: String, item: StackItem<I>) {
+        if self.scopes.is_empty() {
+            self.scopes.push(HashMap::new());
+        }
+        self.scopes.last_mut().unwrap().insert(name, item);
+    }
+
+    /// The value of a local bound in the method being run.
+    pub fn local(&self, name: &str) -> Option<&StackItem<I>> {
+        self.scopes.last().and_then(|scope| scope.get(name))
+    }
+
+    // Gather everything pushed above `depth` into a list.
+    fn collect_list(&mut self, depth: usize) -> Result<()> {
+        if self.stack.0.len() < depth {
+            return Err(Error::StackUnderflow);
         }
+        let items = self.stack.split_off(depth);
+        try!(self.stack.push(StackItem::List(items)));
         Ok(())
     }
 
-    pub fn run_block(&mut self, block: &Block<I>) -> Result<()> {
-        for item in block.0.iter() {
-            try!(self.run(item));
+    fn check_memory_limit(&self) -> Result<()> {
+        if let Some(limit) = self.memory_limit {
+            if self.stack.approx_size() > limit {
+                return Err(Error::MemoryLimit);
+            }
+        }
+        Ok(())
+    }
+
+    // Run `f` one level deeper, failing if that passes `max_depth`.
+    fn nested<F>(&mut self, f: F) -> Result<()>
+            where F: FnOnce(&mut Vm<I>) -> Result<()> {
+        self.allow_tail = false;
+        if let Some(max) = self.max_depth {
+            if self.depth >= max {
+                return Err(Error::RecursionLimit);
+            }
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Parse and run each snippet in turn against a fresh stack, sharing
+    /// methods between them, and collect the result of each.
+    pub fn eval_all(&mut self, snippets: &[&str]) -> Vec<Result<()>>
+            where I: FromStr {
+        snippets.iter().map(|src| {
+            self.stack.clear();
+            let block = try!(parse(src).map_err(|e| Error::ParseError(e.to_string())));
+            self.run_block(&block)
+        }).collect()
+    }
+
+    /// The items on the stack, bottom first. Prefer this to reading
+    /// `stack.0` directly.
+    pub fn stack_items(&self) -> &[StackItem<I>] {
+        self.stack.as_slice()
+    }
+
+    /// Take the items on the stack, bottom first, leaving it empty.
+    pub fn take_stack(&mut self) -> Vec<StackItem<I>> {
+        self.stack.drain()
+    }
+
+    /// Check that the stack holds exactly `n` items, e.g. after a script
+    /// that should leave a single result.
+    pub fn assert_depth(&self, n: usize) -> Result<()> {
+        let found = self.stack.0.len();
+        if found == n {
+            Ok(())
+        } else {
+            Err(Error::UnexpectedDepth { expected: n, found })
+        }
+    }
+
+    /// Push copies of host-provided items onto the stack, e.g. as arguments
+    /// for a script about to be run. If they do not all fit, none are pushed
+    /// and the result is `StackOverflow`.
+    pub fn inject(&mut self, items: &[StackItem<I>]) -> Result<()> {
+        let len = self.stack.0.len();
+        for item in items {
+            if let Err(err) = self.stack.push(item.clone()) {
+                self.stack.truncate(len);
+                return Err(err);
+            }
         }
         Ok(())
     }
 
+    /// Send output written by builtins to `output` instead.
+    pub fn set_output<W>(&mut self, output: W) where W: Write + 'static {
+        self.output = Box::new(output);
+        self.captured = None;
+    }
+
+    /// Read input for builtins from `reader` instead.
+    pub fn set_reader<R>(&mut self, reader: R) where R: BufRead + 'static {
+        self.reader = Box::new(reader);
+    }
+
+    /// Collect output written by builtins in memory, to be read back with
+    /// `take_output`.
+    pub fn capture_output(&mut self) {
+        let capture = Capture(Rc::new(RefCell::new(Vec::new())));
+        self.output = Box::new(capture.clone());
+        self.captured = Some(capture);
+    }
+
+    /// Drain the output collected since `capture_output`, or the last call
+    /// to this. Empty if output is not being captured.
+    pub fn take_output(&mut self) -> String {
+        match self.captured {
+            Some(Capture(ref buf)) => {
+                let bytes = buf.borrow_mut().split_off(0);
+                String::from_utf8_lossy(&bytes).into_owned()
+            },
+            None => String::new(),
+        }
+    }
+
+    /// Define a builtin method. It has no recorded stack effect; see
+    /// `insert_builtin_with_signature`.
     #[inline]
-    pub fn insert_builtin<S>(&mut self, name: S, method: Box<Fn(&mut Vm<I>)
-                   -> Result<()>>) where S: Into<String> {
-        self.methods.insert(name.into(), Rc::new(Method::Builtin(method)));
+    pub fn insert_builtin<S>(&mut self, name: S, method: BuiltinFn<I>)
+            where S: Into<String> {
+        self.insert_method(name.into(), None, Method::Builtin(method));
+    }
+
+    /// Define a builtin method along with its human-readable stack effect,
+    /// e.g. `( a b -- b a )` for `swap`.
+    #[inline]
+    pub fn insert_builtin_with_signature<S, T>(&mut self, name: S, signature: T,
+                   method: BuiltinFn<I>)
+            where S: Into<String>, T: Into<String> {
+        self.insert_method(name.into(), Some(signature.into()), Method::Builtin(method));
+    }
+
+    /// Define a builtin method which keeps mutable state between calls, such
+    /// as a counter.
+    pub fn insert_builtin_mut<S, T>(&mut self, name: S, signature: T,
+                   method: BuiltinMut<I>)
+            where S: Into<String>, T: Into<String> {
+        let method = Method::BuiltinMut(RefCell::new(method));
+        self.insert_method(name.into(), Some(signature.into()), method);
+    }
+
+    /// Define a builtin from a Rust function of one argument, converting the
+    /// argument from the top item and the result back to an item. If the
+    /// top item has the wrong type it is left in place and the call fails
+    /// with `TypeError`; it is also left if the result does not convert.
+    pub fn register1<S, T, A, R, F>(&mut self, name: S, signature: T, f: F)
+            where S: Into<String>, T: Into<String>, A: FromStackItem<I>,
+                  R: IntoStackItem<I>, F: Fn(A) -> R + 'static {
+        self.insert_builtin_with_signature(name, signature, Box::new(move |vm| {
+            let a = match A::from_stack_item(try!(vm.stack.peek())) {
+                Some(a) => a,
+                None => return Err(Error::TypeError),
+            };
+            let result = try!(f(a).into_stack_item());
+            try!(vm.stack.pop());
+            vm.stack.push(result)
+        }));
+    }
+
+    /// Like `register1`, for a function of two arguments taken in stack
+    /// order, so the top item is the second argument.
+    pub fn register2<S, T, A, B, R, F>(&mut self, name: S, signature: T, f: F)
+            where S: Into<String>, T: Into<String>, A: FromStackItem<I>,
+                  B: FromStackItem<I>, R: IntoStackItem<I>,
+                  F: Fn(A, B) -> R + 'static {
+        self.insert_builtin_with_signature(name, signature, Box::new(move |vm| {
+            let (a, b) = try!(vm.stack.pop2());
+            let result = match (A::from_stack_item(&a), B::from_stack_item(&b)) {
+                (Some(a), Some(b)) => f(a, b).into_stack_item(),
+                _ => Err(Error::TypeError),
+            };
+            match result {
+                Ok(result) => vm.stack.push(result),
+                Err(e) => {
+                    try!(vm.stack.push(a));
+                    try!(vm.stack.push(b));
+                    Err(e)
+                },
+            }
+        }));
+    }
+
+    fn insert_method(&mut self, name: String, signature: Option<String>, method: Method<I>) {
+        let method = Rc::new(method);
+        match signature {
+            Some(signature) => self.signatures.insert(name.clone(), signature),
+            None => self.signatures.remove(&name),
+        };
+        self.builtins.insert(name.clone(), method.clone());
+        self.methods.insert(name, method);
+    }
+
+    /// Clear the stack and forget methods defined since the builtins were
+    /// inserted, restoring any builtins that were redefined.
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.methods = self.builtins.clone();
+        self.protected.clear();
+        self.last_error = None;
+        self.quit_requested = None;
+        self.scopes.clear();
+        self.linked.clear();
+    }
+
+    /// Whether a method was defined by `insert_builtin` or similar, rather
+    /// than by a script.
+    pub fn is_builtin(&self, name: &str) -> bool {
+        self.builtins.contains_key(name)
+    }
+
+    /// The stack effect a builtin was registered with, if any.
+    pub fn signature(&self, name: &str) -> Option<&str> {
+        self.signatures.get(name).map(|s| &**s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use super::{Vm, Error, StackEffect};
+    use item::{BlockItem, StackItem};
+    use parse::parse;
+    use builtin;
+
+    #[test]
+    fn test_memory_limit() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_all(&mut vm);
+        vm.memory_limit = Some(1000);
+        assert_eq!(vm.run_block(&parse("1 2 3").unwrap()), Ok(()));
+        let big = format!("\"{}\"", "x".repeat(1000));
+        assert_eq!(vm.run_block(&parse(&big).unwrap()), Err(Error::MemoryLimit));
+        // Repeated copies of one block literal count once.
+        vm.reset();
+        let block = format!("{{ {} }}", big);
+        vm.memory_limit = Some(2000);
+        let program = format!("{} clone clone clone", block);
+        assert_eq!(vm.run_block(&parse(&program).unwrap()), Ok(()));
+        vm.reset();
+        assert_eq!(vm.run_block(&parse(&format!("{} {}", block, block)).unwrap()),
+                   Err(Error::MemoryLimit));
+    }
+
+    #[test]
+    fn test_max_stack() {
+        let mut vm = Vm::<i64>::new();
+        vm.stack.set_max(3);
+        assert_eq!(vm.run_block(&parse("1 2 3").unwrap()), Ok(()));
+        assert_eq!(vm.run_block(&parse("4").unwrap()), Err(Error::StackOverflow));
+        assert_eq!(vm.stack.0.len(), 3);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_all(&mut vm);
+        let builtins = vm.methods.len();
+        let program = parse(":f { 1 } fn :+ { } fn! :+ protect 2 f").unwrap();
+        assert_eq!(vm.run_block(&program), Ok(()));
+        vm.reset();
+        assert!(vm.stack.0.is_empty());
+        assert_eq!(vm.methods.len(), builtins);
+        assert!(!vm.methods.contains_key("f"));
+        assert_eq!(vm.run_block(&parse("2 3 +").unwrap()), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(5)]);
+    }
+
+    #[test]
+    fn test_stack_items() {
+        let mut vm = Vm::<i64>::new();
+        assert_eq!(vm.run_block(&parse("1 2 3").unwrap()), Ok(()));
+        assert_eq!(vm.stack_items(), &[StackItem::Integer(1), StackItem::Integer(2),
+                                       StackItem::Integer(3)]);
+    }
+
+    #[test]
+    fn test_insert_builtin() {
+        let mut vm = Vm::<i64>::new();
+        vm.insert_builtin("one", Box::new(|vm| vm.stack.push(StackItem::Integer(1))));
+        vm.insert_builtin_with_signature("two", "( -- n )",
+            Box::new(|vm| vm.stack.push(StackItem::Integer(2))));
+        assert_eq!(vm.run_block(&parse("one two").unwrap()), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(1), StackItem::Integer(2)]);
+        assert_eq!(vm.signature("one"), None);
+        assert_eq!(vm.signature("two"), Some("( -- n )"));
+    }
+
+    #[test]
+    fn test_builtin_mut() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_control_flow(&mut vm);
+        let mut count = 0;
+        vm.insert_builtin_mut("counter", "( -- i )", Box::new(move |vm| {
+            count += 1;
+            vm.stack.push(StackItem::Integer(count))
+        }));
+        assert_eq!(vm.run_block(&parse("counter counter 2 { counter } times").unwrap()),
+                   Ok(()));
+        assert_eq!(vm.stack.0, (1..5).map(StackItem::Integer).collect::<Vec<_>>());
+        vm.insert_builtin_mut("reenter", "( block -- )", Box::new(|vm| {
+            let block = try!(vm.stack.pop_block());
+            vm.run_block(&block)
+        }));
+        let err = vm.run_block(&parse("{ { } reenter } reenter").unwrap()).unwrap_err();
+        assert_eq!(err, Error::ReentrantCall("reenter".to_string()));
+        assert_eq!(err.to_string(), "Builtin called while already running: reenter");
+    }
+
+    #[test]
+    fn test_register() {
+        fn double(x: i64) -> i64 {
+            x * 2
+        }
+        let mut vm = Vm::<i64>::new();
+        vm.register1("double", "( i -- i )", double);
+        vm.register2("repeat", "( s i -- s )", |s: String, n: i64| s.repeat(n as usize));
+        assert_eq!(vm.run_block(&parse("21 double \"ab\" 3 repeat").unwrap()), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(42),
+                                    StackItem::String("ababab".to_string())]);
+        assert_eq!(vm.run_block(&parse("\"x\" double").unwrap()), Err(Error::TypeError));
+        assert_eq!(vm.run_block(&parse("\"x\" repeat").unwrap()), Err(Error::TypeError));
+        assert_eq!(vm.stack.0.len(), 4);
+        let mut vm = Vm::<i32>::new();
+        vm.register1("widen", "( i -- i )", |x: i64| x << 40);
+        vm.register2("shift", "( i i -- i )", |x: i64, n: i64| x << n);
+        assert_eq!(vm.run_block(&parse("1 widen").unwrap()), Err(Error::IntegerOverflow));
+        assert_eq!(vm.run_block(&parse("40 shift").unwrap()), Err(Error::IntegerOverflow));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(1), StackItem::Integer(40)]);
+    }
+
+    #[test]
+    fn test_take_stack() {
+        let mut vm = Vm::<i64>::new();
+        assert_eq!(vm.run_block(&parse("1 \"two\"").unwrap()), Ok(()));
+        assert_eq!(vm.take_stack(), vec![StackItem::Integer(1),
+                                         StackItem::String("two".to_string())]);
+        assert!(vm.stack_items().is_empty());
+    }
+
+    #[test]
+    fn test_assert_depth() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_arithmetic(&mut vm);
+        assert_eq!(vm.assert_depth(0), Ok(()));
+        assert_eq!(vm.run_block(&parse("1 2 3 +").unwrap()), Ok(()));
+        assert_eq!(vm.assert_depth(2), Ok(()));
+        let err = vm.assert_depth(1).unwrap_err();
+        assert_eq!(err, Error::UnexpectedDepth { expected: 1, found: 2 });
+        assert_eq!(err.to_string(), "Unexpected stack depth: expected 1, found 2");
+    }
+
+    #[test]
+    fn test_inject() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_arithmetic(&mut vm);
+        assert_eq!(vm.inject(&[StackItem::Integer(2), StackItem::Integer(3)]), Ok(()));
+        assert_eq!(vm.run_block(&parse("+").unwrap()), Ok(()));
+        assert_eq!(vm.stack.pop(), Ok(StackItem::Integer(5)));
+        vm.stack.set_max(2);
+        assert_eq!(vm.inject(&[StackItem::Integer(1)]), Ok(()));
+        assert_eq!(vm.inject(&[StackItem::Integer(2), StackItem::Integer(3)]),
+                   Err(Error::StackOverflow));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(1)]);
+    }
+
+    #[test]
+    fn test_list_literal() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_arithmetic(&mut vm);
+        assert_eq!(vm.run_block(&parse("0 [1 2 3 +]").unwrap()), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(0),
+            StackItem::List(vec![StackItem::Integer(1), StackItem::Integer(5)])]);
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_stack_ops(&mut vm);
+        assert_eq!(vm.run_block(&parse("1 [pop]").unwrap()), Err(Error::StackUnderflow));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_boolean_ops(&mut vm);
+        builtin::insert_control_flow(&mut vm);
+        vm.max_depth = Some(50);
+        let nested = |n| format!("{}1{}", "true { ".repeat(n), " } if".repeat(n));
+        assert_eq!(vm.run_block(&parse(&nested(10)).unwrap()), Ok(()));
+        assert_eq!(vm.stack.pop(), Ok(StackItem::Integer(1)));
+        assert_eq!(vm.run_block(&parse(&nested(100)).unwrap()),
+            Err(Error::RecursionLimit));
+        assert_eq!(vm.run_block(&parse(&nested(10)).unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn test_link() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_arithmetic(&mut vm);
+        builtin::insert_control_flow(&mut vm);
+        let linked = vm.link(&parse("1 2 + [3 4 *] 0 3 { 1 + } times").unwrap());
+        // Nothing is looked up by name any more, including the `+` in the
+        // loop body run by `times`.
+        vm.methods.clear();
+        assert_eq!(vm.run_linked(&linked), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(3),
+            StackItem::List(vec![StackItem::Integer(12)]), StackItem::Integer(3)]);
+        let unresolved = vm.link(&parse("1 2 +").unwrap());
+        assert_eq!(vm.run_linked(&unresolved),
+            Err(Error::UnknownMethod("+".to_string())));
+    }
+
+    #[test]
+    fn test_block_literal_is_shared() {
+        let mut vm = Vm::<i64>::new();
+        let large = format!("{{ {} }}", "1 ".repeat(10000));
+        let block = parse(&large).unwrap();
+        for _ in 0..100 {
+            assert_eq!(vm.run_block(&block), Ok(()));
+        }
+        // Every push refers to the literal in the parsed block, not a copy.
+        let literal = match block.0[0] {
+            BlockItem::Literal(StackItem::Block(ref b)) => b.clone(),
+            _ => unreachable!(),
+        };
+        assert_eq!(vm.stack.0.len(), 100);
+        assert!(vm.stack.0.iter().all(|item| match *item {
+            StackItem::Block(ref b) => Rc::ptr_eq(b, &literal),
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn test_run_iterative() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_all(&mut vm);
+        let program = parse(":down { 1 - clone 0 eq not { down } if } fn 100000 down").unwrap();
+        assert_eq!(vm.run_iterative(Rc::new(program)), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(0)]);
+        let program = parse(":jdown { 1 - clone 1 min [{ } { jdown }] jump } fn \
+                             pop 100000 jdown").unwrap();
+        assert_eq!(vm.run_iterative(Rc::new(program)), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(0)]);
+        vm.max_depth = Some(50);
+        let program = parse("100 down").unwrap();
+        assert_eq!(vm.run_iterative(Rc::new(program)), Err(Error::RecursionLimit));
+    }
+
+    #[test]
+    fn test_run_iterative_locals() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_all(&mut vm);
+        let program = parse(":sq { [:n] locals n n * } fn \
+                             :f { [:a :b] locals b sq a } fn 2 3 f").unwrap();
+        assert_eq!(vm.run_iterative(Rc::new(program)), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(9), StackItem::Integer(2)]);
+        assert!(vm.scopes.is_empty());
+    }
+
+    #[test]
+    fn test_eval_all() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_arithmetic(&mut vm);
+        let results = vm.eval_all(&["1 2 +", "1 0 /", "3 4 *"]);
+        assert_eq!(results, vec![Ok(()), Err(Error::DivideByZero), Ok(())]);
+        assert_eq!(vm.stack.0, vec![StackItem::Integer(12)]);
+    }
+
+    #[test]
+    fn test_capture_output() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_io(&mut vm);
+        vm.capture_output();
+        assert_eq!(vm.run_block(&parse("\"hi\" print").unwrap()), Ok(()));
+        assert_eq!(vm.take_output(), "hi\n");
+        assert_eq!(vm.take_output(), "");
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_io(&mut vm);
+        vm.set_reader(Cursor::new("first\r\nsecond"));
+        assert_eq!(vm.run_block(&parse("read-line read-line read-line").unwrap()), Ok(()));
+        assert_eq!(vm.stack.0, vec![StackItem::String("first".to_string()),
+                                    StackItem::String("second".to_string()),
+                                    StackItem::Nil]);
+    }
+
+    #[test]
+    fn test_stack_effect_parse() {
+        assert_eq!(StackEffect::parse("( a b -- c )"),
+                   Some(StackEffect { inputs: 2, outputs: 1 }));
+        assert_eq!(StackEffect::parse("(--)"), Some(StackEffect { inputs: 0, outputs: 0 }));
+        assert_eq!(StackEffect::parse("( ... n -- a )"), None);
+        assert_eq!(StackEffect::parse("( a b )"), None);
+        assert_eq!(StackEffect::parse("a -- b"), None);
+    }
+
+    #[test]
+    fn test_call_hooks() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm::<i64>::new();
+        builtin::insert_arithmetic(&mut vm);
+        let before = calls.clone();
+        vm.before_call = Some(Box::new(move |name, stack| {
+            before.borrow_mut().push(format!("before {} {}", name, stack));
+        }));
+        let after = calls.clone();
+        vm.after_call = Some(Box::new(move |name, stack| {
+            after.borrow_mut().push(format!("after {} {}", name, stack));
+        }));
+        assert_eq!(vm.run_block(&parse("2 3 +").unwrap()), Ok(()));
+        assert_eq!(*calls.borrow(), vec!["before + 2 3 ", "after + 5 "]);
     }
 }
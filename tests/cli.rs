@@ -0,0 +1,77 @@
+//! Tests running the `rustpn` binary.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::process::{self, Command, Output, Stdio};
+
+// Run the binary with the given arguments, feeding `input` on stdin.
+fn run(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rustpn"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Run the binary and return what it printed.
+fn rustpn(args: &[&str], input: &str) -> String {
+    String::from_utf8(run(args, input).stdout).unwrap()
+}
+
+#[test]
+fn test_int_i64() {
+    assert_eq!(rustpn(&["--int=i64"], "2 3 +\n"), "5 \n");
+}
+
+#[test]
+fn test_int_i128() {
+    assert_eq!(rustpn(&["--int=i128"], "9223372036854775807 1 +\n"),
+               "9223372036854775808 \n");
+    let overflow = "170141183460469231731687303715884105727 1 +";
+    assert_eq!(rustpn(&["--int=i128", "-e", overflow], ""),
+               "runtime error: Integer overflow or underflow\n");
+}
+
+#[test]
+fn test_int_bigint() {
+    assert_eq!(rustpn(&["--int=bigint"], "2 3 *\n"), "6 \n");
+    assert_eq!(rustpn(&[], "9223372036854775807 1 +\n"), "9223372036854775808 \n");
+}
+
+#[test]
+fn test_eval_flag() {
+    assert_eq!(rustpn(&["-e", "2 3 +"], ""), "5 \n");
+    assert_eq!(rustpn(&["--int=i64", "--eval", "2 3 *"], ""), "6 \n");
+}
+
+#[test]
+fn test_exit_status() {
+    let name = format!("rustpn-divide-by-zero-{}.rpn", process::id());
+    let path = env::temp_dir().join(name);
+    File::create(&path).unwrap().write_all(b"1 0 /\n").unwrap();
+    let output = run(&[path.to_str().unwrap()], "");
+    fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(run(&["-e", "1 {"], "").status.code(), Some(1));
+    assert_eq!(run(&["-e", "1"], "").status.code(), Some(0));
+    assert_eq!(run(&[], "1 0 /\n").status.code(), Some(0));
+}
+
+#[test]
+fn test_quit() {
+    assert_eq!(run(&["-e", "1 quit 1 0 /"], "").status.code(), Some(0));
+    assert_eq!(run(&["-e", "3 exit"], "").status.code(), Some(3));
+    let output = run(&[], "1\n4 exit\n2\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1 \n");
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn test_error_position() {
+    assert_eq!(rustpn(&["-e", "1\n { 2"], ""), "2:2: parser error: Unclosed block\n");
+    assert_eq!(rustpn(&["-e", "[1 }"], ""), "1:4: parser error: Mismatched bracket\n");
+}